@@ -0,0 +1,338 @@
+//! Check that formatting did not change the meaning of a document, by
+//! comparing the parse trees of the input and the output.
+
+use crate::footnotes::move_to_end;
+use crate::front_matter;
+use crate::parse::{parse, Block, Item, LinkKind, Span};
+use crate::{ErrorKind, FormatError, Options};
+use std::borrow::Cow;
+use std::fmt::Debug;
+
+/// Check that `formatted` parses to the same document as `input`.
+///
+/// Whitespace inside of text is allowed to change, footnotes can be moved
+/// and renamed, and links can be written as references or inline.
+/// Everything else has to be identical. The error names the first node
+/// that differs.
+pub fn verify_equivalent(
+    input: &str,
+    formatted: &str,
+    options: &Options,
+) -> Result<(), FormatError> {
+    let expected = normalize_blocks(move_to_end(parse(input, options)));
+    let actual = normalize_blocks(move_to_end(parse(formatted, options)));
+    compare_blocks(&expected, &actual, "")
+}
+
+/// Definitions are left out, the links they define are compared instead
+fn normalize_blocks(blocks: Vec<Block>) -> Vec<Block> {
+    blocks
+        .into_iter()
+        .filter(|block| !matches!(block, Block::Definition(..)))
+        .map(normalize_block)
+        .collect()
+}
+
+fn normalize_block(block: Block) -> Block {
+    match block {
+        Block::Heading(level, spans) => Block::Heading(level, normalize_spans(spans)),
+        Block::Paragraph(spans) => Block::Paragraph(normalize_spans(spans)),
+        Block::Plain(spans) => Block::Plain(normalize_spans(spans)),
+        Block::Blockquote(kind, blocks) => Block::Blockquote(kind, normalize_blocks(blocks)),
+        Block::List(start, items) => Block::List(
+            start,
+            items
+                .into_iter()
+                .map(|item| Item {
+                    task: item.task,
+                    blocks: normalize_blocks(item.blocks),
+                })
+                .collect(),
+        ),
+        Block::FootnoteDefinition(label, blocks) => {
+            Block::FootnoteDefinition(label, normalize_blocks(blocks))
+        }
+        Block::Table(alignments, header, rows) => Block::Table(
+            alignments,
+            normalize_row(header),
+            rows.into_iter().map(normalize_row).collect(),
+        ),
+        // the info string of fenced code is optional
+        Block::Code(info, code) => {
+            Block::Code(info.filter(|info| !info.is_empty()), ends_line(code))
+        }
+        Block::Html(html) => Block::Html(ends_line(html)),
+        // the keys of front matter can be sorted
+        Block::FrontMatter(text) => {
+            Block::FrontMatter(Cow::Owned(front_matter::sort_keys(&text).into_owned()))
+        }
+        block @ (Block::Verbatim(_) | Block::Definition(..) | Block::Hr) => block,
+    }
+}
+
+fn normalize_row(cells: Vec<Vec<Span>>) -> Vec<Vec<Span>> {
+    cells.into_iter().map(normalize_spans).collect()
+}
+
+/// Join adjacent text and collapse whitespace in it
+fn normalize_spans(spans: Vec<Span>) -> Vec<Span> {
+    let mut result: Vec<Span> = Vec::with_capacity(spans.len());
+    for span in spans {
+        let span = match span {
+            Span::Text(_) | Span::SoftBreak => {
+                let text = match span {
+                    Span::Text(text) => text,
+                    _ => Cow::Borrowed(" "),
+                };
+                if let Some(Span::Text(previous)) = result.last_mut() {
+                    previous.to_mut().push_str(&text);
+                    continue;
+                }
+                Span::Text(text)
+            }
+            // titles over several lines are written on one
+            Span::Link(content, url, title, _) => Span::Link(
+                normalize_spans(content),
+                url,
+                Cow::Owned(collapse_whitespace(&title)),
+                LinkKind::Inline,
+            ),
+            Span::Image(content, url, title, _) => Span::Image(
+                normalize_spans(content),
+                url,
+                Cow::Owned(collapse_whitespace(&title)),
+                LinkKind::Inline,
+            ),
+            Span::Emphasis(spans) => Span::Emphasis(normalize_spans(spans)),
+            Span::Strong(spans) => Span::Strong(normalize_spans(spans)),
+            Span::Strikethrough(spans) => Span::Strikethrough(normalize_spans(spans)),
+            span => span,
+        };
+        result.push(span);
+    }
+
+    let last = result.len().saturating_sub(1);
+    for (i, span) in result.iter_mut().enumerate() {
+        if let Span::Text(text) = span {
+            let mut collapsed = collapse_whitespace(text);
+            if i == 0 {
+                collapsed = collapsed.trim_start().to_owned();
+            }
+            if i == last {
+                collapsed = collapsed.trim_end().to_owned();
+            }
+            *text = Cow::Owned(collapsed);
+        }
+    }
+    result.retain(|span| !matches!(span, Span::Text(text) if text.is_empty()));
+    result
+}
+
+/// The last line of code or HTML at the end of the document may lack its newline
+fn ends_line(mut text: String) -> String {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+/// Replace every run of whitespace with a single space
+fn collapse_whitespace(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut in_whitespace = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !in_whitespace {
+                result.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            result.push(c);
+            in_whitespace = false;
+        }
+    }
+    result
+}
+
+fn difference<T: Debug>(location: &str, expected: Option<&T>, actual: Option<&T>) -> FormatError {
+    let expected = expected.map(|n| format!("{n:?}"));
+    let actual = actual.map(|n| format!("{n:?}"));
+    // long nodes are shown around the first character that differs
+    let offset = match (&expected, &actual) {
+        (Some(e), Some(a)) => e.chars().zip(a.chars()).take_while(|(e, a)| e == a).count(),
+        _ => 0,
+    };
+    let describe = |node: Option<String>| node.map_or("nothing".to_owned(), |n| brief(&n, offset));
+    FormatError::new(ErrorKind::NotEquivalent {
+        node: location.trim_start_matches(" > ").to_owned(),
+        expected: describe(expected),
+        actual: describe(actual),
+    })
+}
+
+/// Shorten long nodes for error messages to the characters around `offset`,
+/// after the name of the node
+fn brief(text: &str, offset: usize) -> String {
+    const MAX: usize = 60;
+    const BEFORE: usize = 20;
+    let chars: Vec<char> = text.chars().collect();
+    let name = text.find('(').map_or(0, |i| text[..=i].chars().count());
+    let start = offset.saturating_sub(BEFORE).max(name);
+    let end = (start + MAX).min(chars.len());
+    let mut result: String = chars[..name.min(start)].iter().collect();
+    if start > name {
+        result.push_str("...");
+    }
+    result.extend(&chars[start..end]);
+    if end < chars.len() {
+        result.push_str("...");
+    }
+    result
+}
+
+fn compare_blocks(expected: &[Block], actual: &[Block], location: &str) -> Result<(), FormatError> {
+    for i in 0..expected.len().max(actual.len()) {
+        let location = format!("{location} > block {}", i + 1);
+        match (expected.get(i), actual.get(i)) {
+            (Some(Block::Heading(el, e)), Some(Block::Heading(al, a))) if el == al => {
+                compare_spans(e, a, &location)?
+            }
+            (Some(Block::Paragraph(e)), Some(Block::Paragraph(a)))
+            | (Some(Block::Plain(e)), Some(Block::Plain(a))) => compare_spans(e, a, &location)?,
+            (Some(Block::Blockquote(ek, e)), Some(Block::Blockquote(ak, a))) if ek == ak => {
+                compare_blocks(e, a, &location)?
+            }
+            (Some(Block::List(es, e)), Some(Block::List(as_, a))) if es == as_ => {
+                compare_items(e, a, &location)?
+            }
+            (Some(Block::FootnoteDefinition(el, e)), Some(Block::FootnoteDefinition(al, a)))
+                if el == al =>
+            {
+                compare_blocks(e, a, &location)?
+            }
+            (e, a) if e == a => {}
+            (e, a) => return Err(difference(&location, e, a)),
+        }
+    }
+    Ok(())
+}
+
+fn compare_items(expected: &[Item], actual: &[Item], location: &str) -> Result<(), FormatError> {
+    for i in 0..expected.len().max(actual.len()) {
+        let location = format!("{location} > item {}", i + 1);
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e.task == a.task => {
+                compare_blocks(&e.blocks, &a.blocks, &location)?
+            }
+            (e, a) => return Err(difference(&location, e, a)),
+        }
+    }
+    Ok(())
+}
+
+fn compare_spans(expected: &[Span], actual: &[Span], location: &str) -> Result<(), FormatError> {
+    for i in 0..expected.len().max(actual.len()) {
+        let location = format!("{location} > span {}", i + 1);
+        match (expected.get(i), actual.get(i)) {
+            (Some(Span::Emphasis(e)), Some(Span::Emphasis(a)))
+            | (Some(Span::Strong(e)), Some(Span::Strong(a)))
+            | (Some(Span::Strikethrough(e)), Some(Span::Strikethrough(a))) => {
+                compare_spans(e, a, &location)?
+            }
+            (Some(Span::Link(e, eu, et, _)), Some(Span::Link(a, au, at, _)))
+            | (Some(Span::Image(e, eu, et, _)), Some(Span::Image(a, au, at, _)))
+                if eu == au && et == at =>
+            {
+                compare_spans(e, a, &location)?
+            }
+            (e, a) if e == a => {}
+            (e, a) => return Err(difference(&location, e, a)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_whitespace_is_equivalent() {
+        verify_equivalent("a *b*  c,\nd\n", "a *b* c, d", &Options::default()).unwrap();
+    }
+
+    #[test]
+    fn test_moved_footnotes_are_equivalent() {
+        verify_equivalent(
+            "[^x]: X\n\na[^Y] b[^x]\n\n[^y]: Y\n",
+            "a[^1] b[^2]\n\n[^1]: Y\n\n[^2]: X\n",
+            &Options::default(),
+        )
+        .unwrap();
+        verify_equivalent(
+            "a[^x] b[^y]\n\n[^x]: X\n\n[^y]: Y\n",
+            "a[^x] b[^y]\n\n[^x]: Y\n\n[^y]: X\n",
+            &Options::default(),
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn test_references_are_equivalent_to_inline_links() {
+        verify_equivalent(
+            "[a](/u \"t\")\n",
+            "[a][X]\n\n[x]: /u \"t\"\n",
+            &Options::default(),
+        )
+        .unwrap();
+        verify_equivalent("[a](/u)\n", "[a][x]\n\n[x]: /v\n", &Options::default()).unwrap_err();
+    }
+
+    #[test]
+    fn test_titles_on_several_lines_are_equivalent() {
+        verify_equivalent(
+            "[a](/u \"x\ny\")\n",
+            "[a](/u \"x y\")\n",
+            &Options::default(),
+        )
+        .unwrap();
+        verify_equivalent(
+            "[a]\n\n[a]: /u\n  \"x\n  y\"\n",
+            "[a]\n\n[a]: /u \"x y\"\n",
+            &Options::default(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_report_first_difference() {
+        let e = verify_equivalent(
+            "# H\n\n* a\n* *b*\n",
+            "# H\n\n* a\n* *c*\n",
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "formatting would change the document at block 2 > item 2 > block 1 > span 1 > \
+             span 1: \
+             Text(\"b\") became Text(\"c\")"
+        );
+    }
+
+    #[test]
+    fn test_report_long_nodes_where_they_differ() {
+        let code = "a line of code that is long enough to be shortened\n".repeat(3);
+        let e = verify_equivalent(
+            &format!("```\n{code}x\n```\n"),
+            &format!("```\n{code}y\n```\n"),
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "formatting would change the document at block 1: \
+             Code(...gh to be shortened\\nx\\n\") became Code(...gh to be shortened\\ny\\n\")"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::{Path, PathBuf};
+use std::{fmt, io};
+
+/// Error while formatting a document
+#[derive(Debug)]
+pub struct FormatError {
+    /// The file that was formatted, if known
+    pub path: Option<PathBuf>,
+
+    /// Line of the input that caused the error, starting at 1, if known
+    pub line: Option<usize>,
+
+    /// What went wrong
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// Reading or writing a file failed
+    Io(io::Error),
+
+    /// The configuration file is invalid
+    Config(Box<toml::de::Error>),
+
+    /// Formatting the output again changes this line of the output
+    NotIdempotent(usize),
+
+    /// The formatted document means something else than the input
+    NotEquivalent {
+        /// Path to the first node that differs
+        node: String,
+        /// The node in the input
+        expected: String,
+        /// The node in the formatted document
+        actual: String,
+    },
+
+    /// A `markdown-format off` or `on` directive without its partner
+    UnbalancedDirective(&'static str),
+
+    /// A bug in the formatter
+    Internal(&'static str),
+}
+
+impl FormatError {
+    pub(crate) fn new(kind: ErrorKind) -> Self {
+        Self {
+            path: None,
+            line: None,
+            kind,
+        }
+    }
+
+    pub(crate) fn internal(what: &'static str) -> Self {
+        Self::new(ErrorKind::Internal(what))
+    }
+
+    pub(crate) fn at_line(mut self, line: Option<usize>) -> Self {
+        self.line = line;
+        self
+    }
+
+    /// Set the file the error happened in
+    pub fn with_path(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        Self::new(ErrorKind::Io(e))
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.path, self.line) {
+            (Some(path), Some(line)) => write!(f, "{}:{line}: ", path.display())?,
+            (Some(path), None) => write!(f, "{}: ", path.display())?,
+            (None, Some(line)) => write!(f, "line {line}: ")?,
+            (None, None) => {}
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Io(e) => write!(f, "{e}"),
+            ErrorKind::Config(e) => write!(f, "invalid configuration: {e}"),
+            ErrorKind::NotIdempotent(line) => write!(
+                f,
+                "formatting is not stable, a second pass changes line {line} of the result"
+            ),
+            ErrorKind::NotEquivalent {
+                node,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "formatting would change the document at {node}: {expected} became {actual}"
+            ),
+            ErrorKind::UnbalancedDirective("off") => write!(
+                f,
+                "`<!-- markdown-format off -->` is not turned on again, the rest is not formatted"
+            ),
+            ErrorKind::UnbalancedDirective(directive) => write!(
+                f,
+                "`<!-- markdown-format {directive} -->` without `off` before it"
+            ),
+            ErrorKind::Internal(what) => write!(f, "internal error: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Io(e) => Some(e),
+            ErrorKind::Config(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Move footnote definitions to the end of the document and number them in
+//! the order they are referenced.
+
+use crate::parse::{append_before_verbatim, visit_spans, Block, Span};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Move all footnote definitions to the end of `blocks` and renumber them
+/// in the order of their first reference.
+///
+/// Labels match ignoring case. Definitions that are never referenced come
+/// last, in their original order.
+pub(crate) fn move_to_end(blocks: Vec<Block<'_>>) -> Vec<Block<'_>> {
+    let mut definitions = Vec::new();
+    let mut blocks = take_definitions(blocks, &mut definitions);
+
+    let mut order: Vec<String> = Vec::new();
+    let mut add = |label: &mut Cow<str>| {
+        let label = label.to_lowercase();
+        if !order.contains(&label) {
+            order.push(label);
+        }
+    };
+    visit_references(&mut blocks, &mut add);
+    // footnotes can reference other footnotes
+    let mut i = 0;
+    while i < order.len() {
+        for (label, content) in &mut definitions {
+            if label.to_lowercase() == order[i] {
+                visit_references(content, &mut |label| {
+                    let label = label.to_lowercase();
+                    if !order.contains(&label) {
+                        order.push(label);
+                    }
+                });
+            }
+        }
+        i += 1;
+    }
+    for (label, _) in &definitions {
+        let label = label.to_lowercase();
+        if !order.contains(&label) {
+            order.push(label);
+        }
+    }
+
+    let numbers: HashMap<String, usize> = order
+        .into_iter()
+        .enumerate()
+        .map(|(i, label)| (label, i + 1))
+        .collect();
+    let number = |label: &str| numbers[&label.to_lowercase()];
+    let mut rename = |label: &mut Cow<str>| *label = Cow::Owned(number(label).to_string());
+
+    visit_references(&mut blocks, &mut rename);
+    for (_, content) in &mut definitions {
+        visit_references(content, &mut rename);
+    }
+    definitions.sort_by_key(|(label, _)| number(label));
+    append_before_verbatim(
+        &mut blocks,
+        definitions.into_iter().map(|(label, content)| {
+            Block::FootnoteDefinition(Cow::Owned(number(&label).to_string()), content)
+        }),
+    );
+    blocks
+}
+
+/// Remove the footnote definitions from `blocks` and everything in them
+fn take_definitions<'a>(
+    blocks: Vec<Block<'a>>,
+    definitions: &mut Vec<(Cow<'a, str>, Vec<Block<'a>>)>,
+) -> Vec<Block<'a>> {
+    let mut result = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        match block {
+            Block::FootnoteDefinition(label, content) => {
+                let content = take_definitions(content, definitions);
+                definitions.push((label, content));
+            }
+            Block::Blockquote(kind, content) => {
+                result.push(Block::Blockquote(
+                    kind,
+                    take_definitions(content, definitions),
+                ));
+            }
+            Block::List(start, mut items) => {
+                for item in &mut items {
+                    item.blocks = take_definitions(std::mem::take(&mut item.blocks), definitions);
+                }
+                result.push(Block::List(start, items));
+            }
+            block => result.push(block),
+        }
+    }
+    result
+}
+
+/// Call `f` with the label of each footnote reference, in document order
+fn visit_references<'a>(blocks: &mut [Block<'a>], f: &mut dyn FnMut(&mut Cow<'a, str>)) {
+    visit_spans(blocks, &mut |span| {
+        if let Span::FootnoteReference(label) = span {
+            f(label);
+        }
+    });
+}
@@ -0,0 +1,133 @@
+//! YAML front matter between `---` lines and TOML front matter between
+//! `+++` lines at the start of a document.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+
+lazy_static! {
+    /// A key at the start of a line of YAML
+    static ref RE_YAML_KEY: Regex = Regex::new(r#"^([^\s#\-\[{][^:]*):(\s|$)"#).unwrap();
+
+    /// A key at the start of a line of TOML
+    static ref RE_TOML_KEY: Regex = Regex::new(r#"^([^\s#\[=][^=]*)="#).unwrap();
+
+    /// YAML anchors and aliases, which depend on the order of the keys
+    static ref RE_YAML_ANCHOR: Regex = Regex::new(r"(^|[\s:,\[{-])[&*][^\s,\]}]").unwrap();
+}
+
+/// Split `source` into the front matter with its delimiters and the rest
+pub(crate) fn split(source: &str) -> (Option<&str>, &str) {
+    let mut lines = source.split_inclusive('\n');
+    let Some(first) = lines.next() else {
+        return (None, source);
+    };
+    let (closing, key): (&[&str], &Regex) = match first.trim_end() {
+        "---" => (&["---", "..."], &RE_YAML_KEY),
+        "+++" => (&["+++"], &RE_TOML_KEY),
+        _ => return (None, source),
+    };
+    let mut end = first.len();
+    let mut content = false;
+    for line in lines {
+        end += line.len();
+        let line = line.trim_end();
+        if closing.contains(&line) {
+            let front_matter = source[..end].trim_end_matches(['\n', '\r']);
+            return (Some(front_matter), &source[end..]);
+        }
+        // a thematic break followed by text is no front matter
+        if !content && !line.is_empty() && !line.starts_with('#') {
+            if !key.is_match(line) {
+                return (None, source);
+            }
+            content = true;
+        }
+    }
+    (None, source)
+}
+
+/// Sort the top level keys of the front matter, with the comments before
+/// each key.
+///
+/// TOML tables stay at the end in their order. If the keys can not be
+/// sorted safely, the front matter is returned as it is.
+pub(crate) fn sort_keys(front_matter: &str) -> Cow<'_, str> {
+    let lines: Vec<&str> = front_matter.lines().collect();
+    let [open, body @ .., close] = lines.as_slice() else {
+        return Cow::Borrowed(front_matter);
+    };
+    let toml = open.trim_end() == "+++";
+    let (key, end) = if toml {
+        let tables = body
+            .iter()
+            .position(|line| line.trim_start().starts_with('['))
+            .unwrap_or(body.len());
+        (&*RE_TOML_KEY, tables)
+    } else {
+        if body.iter().any(|line| RE_YAML_ANCHOR.is_match(line)) {
+            return Cow::Borrowed(front_matter);
+        }
+        (&*RE_YAML_KEY, body.len())
+    };
+
+    let mut entries: Vec<(&str, Vec<&str>)> = Vec::new();
+    // comments and empty lines before the next key
+    let mut pending = Vec::new();
+    for line in &body[..end] {
+        if let Some(captures) = key.captures(line) {
+            let name = captures.get(1).map_or("", |m| m.as_str().trim());
+            let mut entry = std::mem::take(&mut pending);
+            entry.push(*line);
+            entries.push((name.trim_matches(['"', '\'']), entry));
+        } else if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            pending.push(*line);
+        } else if let Some((_, entry)) = entries.last_mut() {
+            entry.append(&mut pending);
+            entry.push(*line);
+        } else {
+            return Cow::Borrowed(front_matter);
+        }
+    }
+    entries.sort_by_key(|(name, _)| *name);
+
+    let mut sorted = vec![*open];
+    sorted.extend(entries.into_iter().flat_map(|(_, entry)| entry));
+    sorted.extend(pending);
+    sorted.extend(&body[end..]);
+    sorted.push(close);
+    let sorted = sorted.join("\n");
+
+    // keys in multi-line strings would be moved out of them
+    if toml {
+        let table = |text: &str| toml::from_str::<toml::Table>(text).ok();
+        let original = table(&body.join("\n"));
+        if original.is_none() || table(&sorted[open.len()..sorted.len() - close.len()]) != original
+        {
+            return Cow::Borrowed(front_matter);
+        }
+    }
+    Cow::Owned(sorted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sort_yaml_keys() {
+        let yaml = "---\ntitle: T\n# the tags\ntags:\n- b\n- a\ndate: 2024-01-01\n---";
+        assert_eq!(
+            sort_keys(yaml),
+            "---\ndate: 2024-01-01\n# the tags\ntags:\n- b\n- a\ntitle: T\n---"
+        );
+        let aliases = "---\nb: &x 1\na: *x\n---";
+        assert_eq!(sort_keys(aliases), aliases);
+    }
+
+    #[test]
+    fn test_no_front_matter() {
+        let text = "---\n\nNot a key\n\n---\n";
+        assert_eq!(split(text), (None, text));
+    }
+}
@@ -0,0 +1,1365 @@
+//! Markdown formatter
+//!
+//! ```
+//! let options = markdown_format::Options::default();
+//! let formatted = markdown_format::format_str("* a\n* b\n", &options).unwrap();
+//! assert_eq!(formatted, "*   a\n*   b\n");
+//! ```
+
+mod equivalence;
+mod error;
+mod footnotes;
+mod front_matter;
+mod options;
+mod parse;
+mod references;
+mod sentences;
+mod wrap;
+
+pub use equivalence::verify_equivalent;
+pub use error::{ErrorKind, FormatError};
+pub use options::{
+    find_config, EmphasisStyle, FootnotePlacement, FrontMatter, HeadingStyle, LineBreaks,
+    LinkDefinitions, ListMarker, Options, StrongStyle, WrapStyle, CONFIG_FILE_NAME,
+};
+
+use parse::{Block, Item, LinkKind, Span};
+use pulldown_cmark::Alignment;
+use references::normalize_label;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use unicode_width::UnicodeWidthStr;
+use wrap::{Layout, Space, Word};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+lazy_static! {
+    /// An entity or character reference like `&amp;`
+    static ref RE_ENTITY: Regex = Regex::new(r"&(#[0-9]{1,7}|#[xX][0-9a-fA-F]{1,6}|[a-zA-Z][a-zA-Z0-9]*);").unwrap();
+
+    /// Opening or closing tag of an element whose text keeps its whitespace
+    static ref RE_RAW_TEXT_TAG: Regex = Regex::new(r"(?i)<(/?)(pre|script)[\s>]").unwrap();
+
+    /// A URL in text that GitHub turns into a link
+    static ref RE_BARE_URL: Regex = Regex::new(r"(https?://|www\.)[^\s<]+").unwrap();
+
+    /// Text that would start a block if it was at the start of a line
+    static ref RE_BLOCK_START: Regex = Regex::new(
+        r"^(#{1,6}(\s|$)|>|[-+*](\s|$)|-+\s*$|=+\s*$|[-*_](\s*[-*_]){2,}\s*$|\d{1,9}[.)](\s|$)|```|~~~|<([!?]|/?[A-Za-z][A-Za-z0-9-]*(\s|/?>|$))|\[\^[^\]]*\]:|\||:?-+:?\s*\|)"
+    )
+    .unwrap();
+}
+
+const CODE_WRAP_LENGTH: usize = 20;
+
+#[derive(Debug)]
+enum Lowered<'input> {
+    /// Whitespace, which becomes a space or a line break
+    Space(Space),
+
+    /// Text should continue in next line. Join with any following breaks
+    Break,
+
+    /// Insert 1 empty line here (depending on what came before, 1 or 2 `'\n'`
+    EmptyLine,
+
+    /// All lines after this get a prefix
+    Prefix(&'static str),
+
+    /// This line gets .0 as prefix, all lower lines get .1
+    Prefix2(String, Cow<'static, str>),
+
+    /// Remove the latest prefix
+    Pop,
+
+    /// A String
+    String(String),
+
+    /// also a String
+    Str(&'input str),
+
+    /// A whole line that is copied as it is, even if it is empty
+    Line(&'input str),
+
+    /// a horizontal ruler
+    Hr,
+}
+
+impl<'i> PartialEq for Lowered<'i> {
+    fn eq(&self, other: &Self) -> bool {
+        match self {
+            Lowered::Space(s) => matches!(other, Lowered::Space(o) if s == o),
+            Lowered::Break => matches!(other, Lowered::Break),
+            Lowered::EmptyLine => matches!(other, Lowered::EmptyLine),
+            Lowered::Pop => matches!(other, Lowered::Pop),
+            Lowered::Hr => matches!(other, Lowered::Hr),
+            Lowered::Prefix(s) => {
+                if let Lowered::Prefix(o) = other {
+                    s == o
+                } else {
+                    false
+                }
+            }
+            Lowered::Prefix2(s, s2) => {
+                if let Lowered::Prefix2(o, o2) = other {
+                    s == o && s2 == o2
+                } else {
+                    false
+                }
+            }
+            Lowered::String(s) => {
+                if let Lowered::String(o) = other {
+                    s == o
+                } else {
+                    if let Lowered::Str(o) = other {
+                        s == o
+                    } else {
+                        false
+                    }
+                }
+            }
+            Lowered::Str(s) => {
+                if let Lowered::String(o) = other {
+                    s == o
+                } else {
+                    if let Lowered::Str(o) = other {
+                        s == o
+                    } else {
+                        false
+                    }
+                }
+            }
+            Lowered::Line(s) => {
+                if let Lowered::Line(o) = other {
+                    s == o
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LoweredBuffer<'input> {
+    buffer: Vec<Lowered<'input>>,
+    options: &'input Options,
+
+    /// Break the line at the next whitespace
+    break_at_space: bool,
+
+    /// Inside of a table cell, where `|` has to be escaped
+    in_table: bool,
+
+    /// Inside of inline `<pre>` or `<script>`, where text is not reflowed
+    in_raw_text: bool,
+}
+
+impl<'input> LoweredBuffer<'input> {
+    fn new(options: &'input Options) -> Self {
+        Self {
+            buffer: vec![],
+            options,
+            break_at_space: false,
+            in_table: false,
+            in_raw_text: false,
+        }
+    }
+
+    fn break_line(&mut self) {
+        self.buffer.push(Lowered::Break);
+    }
+
+    /// Whitespace between inline elements
+    fn space(&mut self) {
+        self.add_space(Space::default());
+    }
+
+    /// Whitespace, joined with whitespace right before it
+    fn add_space(&mut self, space: Space) {
+        let space = Space {
+            apart: space.apart || self.break_at_space,
+            ..space
+        };
+        match self.buffer.last_mut() {
+            None | Some(Lowered::Break) => {}
+            Some(Lowered::Space(last)) => *last = last.join(space),
+            // a backslash at the end of a line would be a hard line break
+            Some(Lowered::Str(s)) if s.ends_with('\\') => self.buffer.push(Lowered::Str(" ")),
+            Some(Lowered::String(s)) if s.ends_with('\\') => self.buffer.push(Lowered::Str(" ")),
+            _ => self.buffer.push(Lowered::Space(space)),
+        }
+    }
+
+    /// Break the line at the whitespace right before this, if there is any
+    fn break_before(&mut self) {
+        if let Some(Lowered::Space(space)) = self.buffer.last_mut() {
+            space.apart = true;
+        }
+    }
+
+    /// Break the line at the whitespace right after this, if there is any
+    fn break_after(&mut self) {
+        self.break_at_space = true;
+    }
+
+    fn empty_line(&mut self) {
+        self.buffer.push(Lowered::EmptyLine);
+    }
+
+    fn write(&mut self, string: &'input str) {
+        self.write_raw(string.trim());
+    }
+    fn write_string(&mut self, string: String) {
+        self.write_raw_string(string.trim().to_owned());
+    }
+
+    /// Write without removing surrounding whitespace
+    fn write_raw(&mut self, string: &'input str) {
+        if !string.is_empty() {
+            assert!(string.chars().all(|c| c != '\n'));
+
+            self.keep_off_line_start(string);
+            self.buffer.push(Lowered::Str(string));
+            self.break_at_space = false;
+        }
+    }
+    fn write_raw_string(&mut self, string: String) {
+        if !string.is_empty() {
+            assert!(string.chars().all(|c| c != '\n'));
+
+            self.keep_off_line_start(&string);
+            self.buffer.push(Lowered::String(string));
+            self.break_at_space = false;
+        }
+    }
+
+    /// Write the opening delimiter of emphasis, which never starts a block
+    fn open(&mut self, delimiter: &'input str) {
+        self.buffer.push(Lowered::Str(delimiter));
+        self.break_at_space = false;
+    }
+
+    /// Replace whitespace right before `string` with a space that is never
+    /// broken, if `string` at the start of a line would start a new block.
+    fn keep_off_line_start(&mut self, string: &str) {
+        if RE_BLOCK_START.is_match(string) {
+            if let Some(last @ Lowered::Space(_)) = self.buffer.last_mut() {
+                *last = Lowered::Str(" ");
+            }
+        }
+    }
+
+    /// Copy `line` to the output as it is
+    fn line(&mut self, line: &'input str) {
+        self.buffer.push(Lowered::Line(line));
+    }
+
+    /// Write prefix, but clear `text_inserted` so maybe_line_feed will not linefeed.
+    fn prefix2(&mut self, this_prefix: String, next_prefix: impl Into<Cow<'static, str>>) {
+        self.buffer
+            .push(Lowered::Prefix2(this_prefix, next_prefix.into()));
+    }
+
+    fn prefix(&mut self, prefix: &'static str) {
+        self.buffer.push(Lowered::Prefix(prefix));
+    }
+
+    fn pop(&mut self) {
+        self.buffer.push(Lowered::Pop);
+    }
+
+    /// Width of the prefixes in front of the next line, including a marker
+    /// that the line starts after
+    fn prefix_width(&self) -> usize {
+        let mut widths = Vec::new();
+        for element in &self.buffer {
+            match element {
+                Lowered::Prefix(prefix) => widths.push(display_width(prefix)),
+                Lowered::Prefix2(_, following) => widths.push(display_width(following)),
+                Lowered::Pop => {
+                    widths.pop();
+                }
+                _ => {}
+            }
+        }
+        let marker = match self.buffer.last() {
+            Some(Lowered::Prefix2(this, following)) => {
+                display_width(this).saturating_sub(display_width(following))
+            }
+            _ => 0,
+        };
+        widths.iter().sum::<usize>() + marker
+    }
+
+    fn hr(&mut self) {
+        self.buffer.push(Lowered::Hr);
+    }
+
+    /// Lower spans that have to stay on one line, like the text of a link
+    fn one_line(&self, spans: &[Span]) -> Result<String, FormatError> {
+        let mut buffer = LoweredBuffer::new(self.options);
+        buffer.in_table = self.in_table;
+        buffer.lower_spans(spans)?;
+        lowered_to_one_line(&buffer.buffer)
+    }
+
+    fn lower_spans(&mut self, spans: &'input [Span]) -> Result<(), FormatError> {
+        for (i, span) in spans.iter().enumerate() {
+            match span {
+                Span::HardBreak => {
+                    self.write_raw("\\");
+                    self.break_line();
+                }
+                Span::SoftBreak if self.in_raw_text => self.break_line(),
+                Span::SoftBreak => self.add_space(Space {
+                    newline: true,
+                    ..Space::default()
+                }),
+                Span::Text(text) if self.in_raw_text => self.write_raw(text),
+                Span::Text(text) => {
+                    let mut text: &str = text;
+                    while let Some((before, url, after)) = split_bare_url(text) {
+                        self.lower_text(before);
+                        self.break_before();
+                        self.write_raw(url);
+                        self.break_after();
+                        text = after;
+                    }
+                    self.lower_text(text);
+                }
+                Span::Code(code) => {
+                    if display_width(code) > CODE_WRAP_LENGTH {
+                        self.break_before();
+                    }
+                    self.write_raw_string(self.escape_pipes(code_span(code)));
+                    if display_width(code) > CODE_WRAP_LENGTH {
+                        self.break_after();
+                    }
+                }
+                Span::Link(content, url, title, kind) => {
+                    self.break_before();
+                    let text = self.one_line(content)?;
+                    let target = self.escape_pipes(link_target(&text, url, title, kind));
+                    self.write_raw_string(format!("[{text}]{target}"));
+                    self.break_after();
+                }
+                Span::Image(content, url, title, kind) => {
+                    self.break_before();
+                    let text = self.one_line(content)?;
+                    let target = self.escape_pipes(link_target(&text, url, title, kind));
+                    self.write_raw_string(format!("![{text}]{target}"));
+                    self.break_after();
+                }
+                Span::Math(math) => {
+                    let math = self.escape_pipes(format!("${math}$"));
+                    for (n, line) in math.split('\n').enumerate() {
+                        if n > 0 {
+                            self.break_line();
+                        }
+                        self.write_raw_string(line.to_owned());
+                    }
+                }
+                Span::DisplayMath(math) => {
+                    // display math on more than one line gets lines of its own
+                    let math = self.escape_pipes(format!("$${math}$$"));
+                    if math.contains('\n') {
+                        self.break_before();
+                    }
+                    for (n, line) in math.split('\n').enumerate() {
+                        if n > 0 {
+                            self.break_line();
+                        }
+                        self.write_raw_string(line.to_owned());
+                    }
+                    if math.contains('\n') {
+                        self.break_after();
+                    }
+                }
+                Span::Autolink(url) => {
+                    self.break_before();
+                    self.write_raw_string(self.escape_pipes(format!("<{url}>")));
+                    self.break_after();
+                }
+                Span::Html(html) if self.in_table => {
+                    self.write_raw_string(html.replace('|', "\\|"));
+                }
+                Span::Html(html) => {
+                    for (n, line) in html.split('\n').enumerate() {
+                        if n > 0 {
+                            self.break_line();
+                        }
+                        self.write_raw(line);
+                    }
+                    for tag in RE_RAW_TEXT_TAG.captures_iter(html) {
+                        self.in_raw_text = tag[1].is_empty();
+                    }
+                }
+                Span::FootnoteReference(label) => {
+                    self.write_raw_string(self.escape_pipes(format!("[^{label}]")));
+                }
+                Span::Emphasis(content) => {
+                    // `_` does not work inside of words
+                    let delimiter = if is_intraword(spans, i) {
+                        "*"
+                    } else {
+                        self.options.emphasis.as_str()
+                    };
+                    self.open(delimiter);
+                    self.lower_spans(content)?;
+                    self.write(delimiter);
+                }
+                Span::Strong(content) => {
+                    let delimiter = if is_intraword(spans, i) {
+                        "**"
+                    } else {
+                        self.options.strong.as_str()
+                    };
+                    self.open(delimiter);
+                    self.lower_spans(content)?;
+                    self.write(delimiter);
+                }
+                Span::Strikethrough(content) => {
+                    let text = self.one_line(content)?;
+                    self.write_raw_string(format!("~~{text}~~"));
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Text, cut into words
+    fn lower_text(&mut self, text: &'input str) {
+        if text.starts_with(|c: char| c.is_ascii_whitespace()) {
+            self.space();
+        }
+        for (i, word) in text.split_ascii_whitespace().enumerate() {
+            if i > 0 {
+                self.add_space(Space {
+                    in_text: true,
+                    ..Space::default()
+                });
+            }
+            self.write_raw(word);
+        }
+        if text.ends_with(|c: char| c.is_ascii_whitespace()) {
+            self.space();
+        }
+    }
+
+    fn lower_header(&mut self, spans: &[Span], level: usize) -> Result<(), FormatError> {
+        let text: String = self.one_line(spans)?;
+        match (level, self.options.heading) {
+            (1 | 2, HeadingStyle::Setext)
+                if !text.is_empty() && !RE_BLOCK_START.is_match(&text) =>
+            {
+                // shorter underlines are not recognized by all parsers
+                let length = display_width(&text).max(3);
+                let bar = if level == 1 {
+                    "=".repeat(length)
+                } else {
+                    "-".repeat(length)
+                };
+
+                self.write_string(text);
+                self.break_line();
+                self.write_string(bar);
+            }
+            (level, _) if level > 0 => {
+                let hashes = "#".repeat(level);
+                self.write_string(format!("{hashes} {text}"));
+            }
+            _ => return Err(FormatError::internal("header level 0")),
+        };
+        Ok(())
+    }
+
+    /// Lower `blocks`, separated by empty lines or, in tight lists, by line breaks
+    fn lower_blocks(&mut self, blocks: &'input [Block], tight: bool) -> Result<(), FormatError> {
+        let mut alternate = false;
+        for (i, block) in blocks.iter().enumerate() {
+            match (i, blocks[..i].last(), block) {
+                (0, ..) => {}
+                // definitions are listed without empty lines between them
+                (_, Some(Block::Definition(..)), Block::Definition(..)) => self.break_line(),
+                _ => self.separate(tight),
+            }
+            // an inline `<pre>` that is never closed ends with its block
+            self.in_raw_text = false;
+            match block {
+                Block::FrontMatter(text) => {
+                    for line in text.lines() {
+                        self.line(line);
+                    }
+                }
+                Block::Heading(level, spans) => self.lower_header(spans, *level)?,
+                Block::Paragraph(spans) | Block::Plain(spans) => {
+                    self.lower_spans(spans)?;
+                }
+                Block::Blockquote(None, blocks) if blocks.is_empty() => self.write(">"),
+                Block::Blockquote(kind, blocks) => {
+                    self.prefix("> ");
+                    if let Some(kind) = kind {
+                        // the kind of an alert is on a line of its own
+                        self.write_string(format!("[!{kind}]"));
+                        self.break_line();
+                    }
+                    self.lower_blocks(blocks, false)?;
+                    self.pop();
+                }
+                // indented code right after a list marker would be indented too much
+                Block::Code(None, code)
+                    if i > 0 || !matches!(self.buffer.last(), Some(Lowered::Prefix2(..))) =>
+                {
+                    self.prefix("    ");
+                    for line in code.lines() {
+                        self.line(line);
+                    }
+                    self.pop();
+                }
+                Block::Code(info, code) => {
+                    let info = info.as_deref().unwrap_or_default();
+                    let fence = code_fence(info, code);
+                    self.write_string(format!("{fence}{info}"));
+                    self.break_line();
+                    for line in code.lines() {
+                        self.line(line);
+                    }
+                    self.write_string(fence);
+                }
+                Block::List(start, items) => {
+                    // a list right after another one would continue it with the same marker
+                    alternate = !alternate
+                        && matches!(
+                            blocks[..i].last(),
+                            Some(Block::List(previous, _)) if previous.is_some() == start.is_some()
+                        );
+                    self.lower_list(*start, items, alternate)?;
+                }
+                Block::Html(html) => {
+                    for line in html.lines() {
+                        self.line(line);
+                    }
+                }
+                Block::Verbatim(text) => {
+                    for line in text.lines() {
+                        self.line(line);
+                    }
+                }
+                Block::FootnoteDefinition(label, blocks) if blocks.is_empty() => {
+                    self.write_string(format!("[^{label}]:"));
+                }
+                Block::FootnoteDefinition(label, blocks) => {
+                    self.prefix2(format!("[^{label}]: "), "    ");
+                    self.lower_blocks(blocks, false)?;
+                    self.pop();
+                }
+                Block::Definition(label, url, title) => {
+                    let label = normalize_label(label);
+                    let destination = link_destination(url, title);
+                    self.write_string(format!("[{label}]: {destination}"));
+                }
+                Block::Table(alignments, header, rows) => {
+                    self.lower_table(alignments, header, rows, self.prefix_width())?;
+                }
+                Block::Hr => {
+                    self.hr();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Separate blocks by an empty line or, in tight lists, by a line break
+    fn separate(&mut self, tight: bool) {
+        if tight {
+            self.break_line();
+        } else {
+            self.empty_line();
+        }
+    }
+
+    fn lower_list(
+        &mut self,
+        start: Option<u64>,
+        items: &'input [Item],
+        alternate: bool,
+    ) -> Result<(), FormatError> {
+        // items of loose lists are paragraphs
+        let tight = !items
+            .iter()
+            .flat_map(|item| &item.blocks)
+            .any(|block| matches!(block, Block::Paragraph(_)));
+        let bullet = match (alternate, self.options.list_marker) {
+            (false, marker) => marker.as_str(),
+            (true, ListMarker::Star) => "-",
+            (true, _) => "*",
+        };
+
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.separate(tight);
+            }
+            let marker = match start {
+                Some(start) => format!("{}{}", start + i as u64, if alternate { ')' } else { '.' }),
+                None => bullet.to_owned(),
+            };
+            let checkbox = item.task.map(|checked| if checked { "[x]" } else { "[ ]" });
+            if item.blocks.is_empty() {
+                self.write_string(format!("{marker} {}", checkbox.unwrap_or_default()));
+                continue;
+            }
+            // later blocks of the item are indented as far as its first line
+            let marker = format!("{marker:<3} ");
+            let indent = " ".repeat(marker.len());
+            self.prefix2(marker, indent);
+            match checkbox {
+                Some(checkbox) => {
+                    // wrapped lines of the first paragraph line up with its text
+                    let (first, rest) = item.blocks.split_at(1);
+                    self.prefix2(format!("{checkbox} "), "    ");
+                    self.lower_blocks(first, tight)?;
+                    self.pop();
+                    if !rest.is_empty() {
+                        self.separate(tight);
+                        self.lower_blocks(rest, tight)?;
+                    }
+                }
+                None => self.lower_blocks(&item.blocks, tight)?,
+            }
+            self.pop();
+        }
+        Ok(())
+    }
+
+    /// Lower a table, with the columns padded to the same width if the table
+    /// still fits into a line after `prefix_width` columns of prefixes then.
+    fn lower_table(
+        &mut self,
+        alignments: &[Alignment],
+        header: &[Vec<Span>],
+        rows: &[Vec<Vec<Span>>],
+        prefix_width: usize,
+    ) -> Result<(), FormatError> {
+        let header = self.table_row(header)?;
+        let rows = rows
+            .iter()
+            .map(|row| self.table_row(row))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // the delimiter row needs room for `:-:`
+        let mut widths = vec![3; alignments.len()];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(display_width(cell));
+            }
+        }
+        // `| ` before each cell, ` ` after it and the final `|`
+        let padded_width: usize = widths.iter().map(|w| w + 3).sum::<usize>() + 1;
+        if prefix_width + padded_width > self.options.width {
+            widths.fill(0);
+        }
+
+        let delimiters = alignments
+            .iter()
+            .zip(&widths)
+            .map(|(alignment, &width)| {
+                let width = width.max(3);
+                match alignment {
+                    Alignment::None => "-".repeat(width),
+                    Alignment::Left => format!(":{}", "-".repeat(width - 1)),
+                    Alignment::Center => format!(":{}:", "-".repeat(width - 2)),
+                    Alignment::Right => format!("{}:", "-".repeat(width - 1)),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        self.write_string(table_line(&header, alignments, &widths));
+        self.break_line();
+        self.write_string(format!("| {} |", delimiters.join(" | ")));
+        for row in &rows {
+            self.break_line();
+            self.write_string(table_line(row, alignments, &widths));
+        }
+        Ok(())
+    }
+
+    /// Content of the cells of a table row
+    fn table_row(&self, cells: &[Vec<Span>]) -> Result<Vec<String>, FormatError> {
+        let mut buffer = LoweredBuffer::new(self.options);
+        buffer.in_table = true;
+        cells.iter().map(|cell| buffer.one_line(cell)).collect()
+    }
+
+    /// `string` with `|` escaped if it goes into a table cell
+    fn escape_pipes(&self, string: String) -> String {
+        if self.in_table {
+            string.replace('|', "\\|")
+        } else {
+            string
+        }
+    }
+}
+
+/// A row of a table with each cell padded to the width of its column
+fn table_line(cells: &[String], alignments: &[Alignment], widths: &[usize]) -> String {
+    let mut line = String::from("|");
+    for ((cell, alignment), width) in cells.iter().zip(alignments).zip(widths) {
+        let padding = width.saturating_sub(display_width(cell));
+        let (left, right) = match alignment {
+            Alignment::None | Alignment::Left => (0, padding),
+            Alignment::Center => (padding / 2, padding - padding / 2),
+            Alignment::Right => (padding, 0),
+        };
+        line.push(' ');
+        line.push_str(&" ".repeat(left));
+        line.push_str(cell);
+        line.push_str(&" ".repeat(right));
+        line.push_str(" |");
+    }
+    line
+}
+
+/// Number of columns `text` takes up in a terminal.
+///
+/// Grapheme clusters like emoji joined with zero-width joiners count as one
+/// character, East Asian wide characters take two columns.
+fn display_width(text: &str) -> usize {
+    UnicodeWidthStr::width(text)
+}
+
+/// Whether the span at `index` is right next to a letter or digit
+fn is_intraword(spans: &[Span], index: usize) -> bool {
+    let before = index
+        .checked_sub(1)
+        .and_then(|i| match &spans[i] {
+            Span::Text(text) => text.chars().next_back(),
+            _ => None,
+        })
+        .is_some_and(char::is_alphanumeric);
+    let after = match spans.get(index + 1) {
+        Some(Span::Text(text)) => text.chars().next().is_some_and(char::is_alphanumeric),
+        _ => false,
+    };
+    before || after
+}
+
+/// `code` in backticks, so that it parses back to `code`
+fn code_span(code: &str) -> String {
+    // backslashes do not escape in code, use a longer fence instead
+    let longest_run = code.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run + 1);
+    // parsing removes one space on both sides
+    let padding = if code.starts_with('`')
+        || code.ends_with('`')
+        || (code.starts_with(' ') && code.ends_with(' ') && !code.trim().is_empty())
+    {
+        " "
+    } else {
+        ""
+    };
+    format!("{fence}{padding}{code}{padding}{fence}")
+}
+
+/// A fence for a code block that is longer than any fence in `code`
+fn code_fence(info: &str, code: &str) -> String {
+    // the info string of a backtick fence can not contain backticks
+    let c = if info.contains('`') { '~' } else { '`' };
+    let longest_fence = code
+        .lines()
+        .map(|line| line.trim_start().chars().take_while(|&x| x == c).count())
+        .max()
+        .unwrap_or(0);
+    c.to_string().repeat(longest_fence.max(2) + 1)
+}
+
+/// Split `text` at the first URL that GitHub turns into a link
+fn split_bare_url(text: &str) -> Option<(&str, &str, &str)> {
+    let found = RE_BARE_URL.find_iter(text).find(|url| {
+        text[..url.start()]
+            .chars()
+            .next_back()
+            .is_none_or(|c| c.is_whitespace() || "(*_~".contains(c))
+    })?;
+    let mut url = found.as_str();
+    loop {
+        // punctuation after the url and unbalanced parentheses are not part of it
+        let mut trimmed = url.trim_end_matches(['?', '!', '.', ',', ':', '*', '_', '~', '\'', '"']);
+        if trimmed.ends_with(')') && trimmed.matches(')').count() > trimmed.matches('(').count() {
+            trimmed = &trimmed[..trimmed.len() - 1];
+        }
+        if trimmed.len() == url.len() {
+            break;
+        }
+        url = trimmed;
+    }
+    let end = found.start() + url.len();
+    Some((&text[..found.start()], url, &text[end..]))
+}
+
+/// `(url "title")` of an inline link or image, or the label of a reference
+fn link_target(text: &str, url: &str, title: &str, kind: &LinkKind) -> String {
+    // the text of collapsed and shortcut references is their label
+    let is_label = |label: &str| normalize_label(text) == normalize_label(label);
+    match kind {
+        LinkKind::Inline => format!("({})", link_destination(url, title)),
+        LinkKind::Collapsed(label) if is_label(label) => "[]".to_owned(),
+        LinkKind::Shortcut(label) if is_label(label) => String::new(),
+        LinkKind::Full(label) | LinkKind::Collapsed(label) | LinkKind::Shortcut(label) => {
+            format!("[{}]", normalize_label(label))
+        }
+    }
+}
+
+/// `url "title"` of an inline link or a link reference definition
+fn link_destination(url: &str, title: &str) -> String {
+    let mut url = escape_entities(&url.replace('\\', "\\\\"));
+    let open = url.matches('(').count();
+    let close = url.matches(')').count();
+    if url.is_empty() || url.contains([' ', '<', '>']) || open != close {
+        url = format!("<{}>", url.replace('<', "\\<").replace('>', "\\>"));
+    }
+    if title.is_empty() {
+        url
+    } else {
+        let title = escape_entities(&title.replace('\\', "\\\\"))
+            .replace('"', "\\\"")
+            .replace('\n', " ");
+        format!("{url} \"{title}\"")
+    }
+}
+
+/// Escape `&` where it would start an entity
+fn escape_entities(text: &str) -> String {
+    RE_ENTITY.replace_all(text, "\\$0").into_owned()
+}
+
+fn lower<'input>(
+    markdown: &'input [Block],
+    options: &'input Options,
+) -> Result<Vec<Lowered<'input>>, FormatError> {
+    let mut buffer = LoweredBuffer::new(options);
+    buffer.lower_blocks(markdown, false)?;
+    Ok(buffer.buffer)
+}
+
+fn fix_line_breaks<'i>(input: Vec<Lowered<'i>>, options: &Options) -> Vec<Lowered<'i>> {
+    let strategy = wrap::strategy(options);
+    let mut input = VecDeque::from(input);
+
+    let mut result = Vec::with_capacity(input.len());
+    let mut line_length = 0;
+    // width of the prefixes that start each line, like `> ` and indentation
+    let mut prefixes: Vec<usize> = Vec::new();
+    // text and whitespace up to the next line break
+    let mut paragraph = Vec::new();
+
+    // remove all breaks from the front
+    while let Some(Lowered::EmptyLine | Lowered::Break | Lowered::Space(_)) = input.front() {
+        input.pop_front();
+    }
+    // remove all breaks from the back
+    while let Some(Lowered::EmptyLine | Lowered::Break | Lowered::Space(_)) = input.back() {
+        input.pop_back();
+    }
+    // add 1 newline, so the file behaves like a good unix file
+    input.push_back(Lowered::Break);
+
+    while let Some(element) = input.pop_front() {
+        let in_paragraph = matches!(
+            element,
+            Lowered::Space(_) | Lowered::Str(_) | Lowered::String(_)
+        );
+        if !in_paragraph && !paragraph.is_empty() {
+            let layout = Layout {
+                start: line_length,
+                indent: prefixes.iter().sum(),
+                width: options.width,
+            };
+            line_length = wrap_paragraph(
+                std::mem::take(&mut paragraph),
+                layout,
+                strategy.as_ref(),
+                &mut result,
+            );
+        }
+        match element {
+            Lowered::Space(_) | Lowered::Str(_) | Lowered::String(_) => paragraph.push(element),
+            Lowered::Break | Lowered::EmptyLine | Lowered::Line(_) | Lowered::Hr => {
+                result.push(element);
+                line_length = prefixes.iter().sum();
+            }
+            Lowered::Prefix(prefix) => {
+                // a quote can start on the line of a marker
+                let at_line_start = line_length == prefixes.iter().sum()
+                    || matches!(result.last(), Some(Lowered::Prefix2(..)));
+                prefixes.push(display_width(prefix));
+                if at_line_start {
+                    line_length += display_width(prefix);
+                }
+                result.push(element);
+            }
+            Lowered::Pop => {
+                let at_line_start = line_length == prefixes.iter().sum();
+                prefixes.pop();
+                if at_line_start {
+                    line_length = prefixes.iter().sum();
+                }
+                result.push(element);
+            }
+            Lowered::Prefix2(ref this, ref following) => {
+                // the first line continues after the marker, which can be wider
+                // than the indentation of the lines below it
+                line_length += display_width(this);
+                prefixes.push(display_width(following));
+                result.push(element);
+                // the first line of a list item starts right after its marker
+                while let Some(Lowered::Break | Lowered::Space(_)) = input.front() {
+                    input.pop_front();
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// Break the text and whitespace of `paragraph` into lines as `strategy`
+/// decides, and return the width of the last line
+fn wrap_paragraph<'i>(
+    paragraph: Vec<Lowered<'i>>,
+    layout: Layout,
+    strategy: &dyn wrap::Strategy,
+    result: &mut Vec<Lowered<'i>>,
+) -> usize {
+    // the elements of each word, and the whitespace between them
+    let mut words: Vec<Vec<Lowered>> = Vec::new();
+    let mut spaces = Vec::new();
+    let mut space: Option<Space> = None;
+    for element in paragraph {
+        match element {
+            Lowered::Space(s) => space = Some(space.map_or(s, |space| space.join(s))),
+            element => {
+                match (words.last_mut(), space.take()) {
+                    (Some(word), None) => word.push(element),
+                    (Some(_), Some(space)) => {
+                        spaces.push(space);
+                        words.push(vec![element]);
+                    }
+                    // whitespace at the start of a line is dropped
+                    (None, _) => words.push(vec![element]),
+                }
+            }
+        }
+    }
+    let texts: Vec<Word> = words
+        .iter()
+        .map(|elements| {
+            let text: String = elements
+                .iter()
+                .map(|element| match element {
+                    Lowered::Str(s) => s,
+                    Lowered::String(s) => s.as_str(),
+                    _ => "",
+                })
+                .collect();
+            let width = display_width(&text);
+            Word { text, width }
+        })
+        .collect();
+    let breaks = strategy.breaks(&texts, &spaces, layout);
+
+    let mut line_length = layout.start;
+    for (i, word) in words.into_iter().enumerate() {
+        if i > 0 {
+            if breaks[i - 1] {
+                result.push(Lowered::Break);
+                line_length = layout.indent;
+            } else {
+                result.push(Lowered::Str(" "));
+                line_length += 1;
+            }
+        }
+        line_length += texts[i].width;
+        result.extend(word);
+    }
+    line_length
+}
+
+struct Formatter {
+    buffer: String,
+    prefixes: Vec<Cow<'static, str>>,
+    newlines: usize,
+    width: usize,
+
+    /// Nothing was written since the last list marker
+    after_marker: bool,
+}
+
+impl Formatter {
+    fn lf(&mut self) {
+        if self.newlines > 0 {
+            // empty lines inside of quotes keep the `>`
+            let prefix = self.prefixes.concat();
+            self.buffer.push_str(prefix.trim_end());
+        }
+        self.buffer.push('\n');
+        self.newlines += 1;
+    }
+    fn write(&mut self, s: &str) {
+        if self.newlines > 0 {
+            for p in &self.prefixes {
+                self.buffer.push_str(p);
+            }
+        }
+        self.buffer.push_str(s);
+        self.newlines = 0;
+        self.after_marker = false;
+    }
+    fn format(&mut self, element: &Lowered) -> Result<(), FormatError> {
+        match element {
+            Lowered::Space(_) => self.write(" "),
+            Lowered::Break => {
+                if self.newlines == 0 {
+                    self.lf()
+                }
+            }
+            Lowered::EmptyLine => match self.newlines {
+                0 => {
+                    self.lf();
+                    self.lf();
+                }
+                1 => self.lf(),
+                _ => {} // already after an empty line
+            },
+            Lowered::Prefix(p) => {
+                if self.after_marker {
+                    // a quote that starts on the line of the marker
+                    self.buffer.push_str(p);
+                }
+                self.prefixes.push(Cow::Borrowed(p));
+            }
+            Lowered::Prefix2(this, following) => {
+                self.write(this);
+                self.prefixes.push(following.clone());
+                self.after_marker = true;
+            }
+            Lowered::Pop => {
+                self.prefixes
+                    .pop()
+                    .ok_or(FormatError::internal("Pop without Prefix"))?;
+            }
+            Lowered::String(s) => self.write(s),
+            Lowered::Str(s) => self.write(s),
+            Lowered::Line(s) => {
+                if self.newlines == 0 && !self.after_marker {
+                    self.lf();
+                }
+                if !s.is_empty() {
+                    self.write(s);
+                }
+                self.lf();
+            }
+            Lowered::Hr => {
+                match self.newlines {
+                    0 => {
+                        self.lf();
+                        self.lf();
+                    }
+                    1 => self.lf(),
+                    _ => {} // already after an empty line
+                }
+                let prefix_len: usize = self.prefixes.iter().map(|s| display_width(s)).sum();
+                let l = if prefix_len + 10 > self.width {
+                    10
+                } else {
+                    self.width - prefix_len
+                };
+                self.write(&"-".repeat(l));
+                self.lf();
+                self.lf();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lowered_to_text(elements: &[Lowered<'_>], options: &Options) -> Result<String, FormatError> {
+    let mut f = Formatter {
+        buffer: String::new(),
+        prefixes: Vec::new(),
+        // the start of the document is like the start of a block
+        newlines: 2,
+        width: options.width,
+        after_marker: false,
+    };
+
+    for e in elements {
+        f.format(e)?;
+    }
+
+    Ok(f.buffer)
+}
+
+fn lowered_to_one_line(elements: &[Lowered<'_>]) -> Result<String, FormatError> {
+    let mut result = String::new();
+    let mut iter = elements.iter().peekable();
+    while let Some(element) = iter.next() {
+        match element {
+            Lowered::EmptyLine => {}
+            Lowered::Space(_) | Lowered::Break => {
+                if iter.peek().is_some() && !result.is_empty() && !result.ends_with(' ') {
+                    result.push(' ');
+                }
+            }
+            Lowered::Prefix(_) => return Err(FormatError::internal("Prefix in 1liner")),
+            Lowered::Prefix2(_, _) => return Err(FormatError::internal("Prefix2 in 1liner")),
+            Lowered::Pop => return Err(FormatError::internal("Pop in 1liner")),
+            Lowered::String(s) => result.push_str(s),
+            Lowered::Str(s) => result.push_str(s),
+            Lowered::Line(_) => return Err(FormatError::internal("Line in 1liner")),
+            Lowered::Hr => return Err(FormatError::internal("HR in 1liner")),
+        }
+    }
+    Ok(result)
+}
+
+/// Parse `input`, sort front matter and move footnotes and link definitions
+/// where they go
+fn parse_document<'a>(input: &'a str, options: &Options) -> Vec<Block<'a>> {
+    let mut md = parse::parse(input, options);
+    if options.footnotes == FootnotePlacement::End {
+        md = footnotes::move_to_end(md);
+    }
+    if options.front_matter == FrontMatter::Sort {
+        if let Some(Block::FrontMatter(text)) = md.first_mut() {
+            *text = Cow::Owned(front_matter::sort_keys(text).into_owned());
+        }
+    }
+    references::arrange_definitions(md, input, options)
+}
+
+/// Format the markdown document `input`
+pub fn format_str(input: &str, options: &Options) -> Result<String, FormatError> {
+    let md = parse_document(input, options);
+    let lowered = lower(&md, options)?;
+    lowered_to_text(&fix_line_breaks(lowered, options), options)
+}
+
+/// Problems in `input` that do not stop it from being formatted, like
+/// unbalanced `<!-- markdown-format off -->` directives
+pub fn warnings(input: &str) -> Vec<FormatError> {
+    parse::unbalanced_directives(input)
+        .into_iter()
+        .map(|(offset, directive)| {
+            let line = input[..offset].matches('\n').count() + 1;
+            FormatError::new(ErrorKind::UnbalancedDirective(directive)).at_line(Some(line))
+        })
+        .collect()
+}
+
+/// Check that formatting `formatted` again does not change it.
+///
+/// This holds for all output of [`format_str`], an error is a bug in the
+/// formatter.
+pub fn verify_idempotent(formatted: &str, options: &Options) -> Result<(), FormatError> {
+    let again = format_str(formatted, options)?;
+    if again == formatted {
+        return Ok(());
+    }
+    let line = formatted
+        .split('\n')
+        .zip(again.split('\n'))
+        .position(|(a, b)| a != b)
+        .unwrap_or_else(|| formatted.split('\n').count().min(again.split('\n').count()));
+    Err(FormatError::new(ErrorKind::NotIdempotent(line + 1)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use Lowered::*;
+
+    #[test]
+    fn test_files() {
+        let temp = std::env::temp_dir();
+        let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"));
+        let rd = path.read_dir();
+        if let Ok(rd) = rd {
+            for c in rd.flatten() {
+                let file_name = c.file_name().into_string().unwrap();
+                if file_name.ends_with(".in.md") {
+                    let test_name = &file_name[..file_name.len() - 6];
+                    let p_in = c.path();
+                    let p_out = path.join(format!("{}.out.md", test_name));
+                    let input = fs::read_to_string(p_in).unwrap();
+                    let expected_output = fs::read_to_string(p_out).unwrap();
+                    let p_options = path.join(format!("{}.toml", test_name));
+                    let options = if p_options.exists() {
+                        Options::load(&p_options).unwrap()
+                    } else {
+                        Options::default()
+                    };
+
+                    eprintln!("{}/{}.in.md", path.display(), test_name);
+                    eprintln!("{}/{}.out.md", path.display(), test_name);
+                    let md = parse_document(&input, &options);
+                    fs::write(
+                        temp.join(format!("{}.phase1", test_name)),
+                        format!("{md:#?}"),
+                    )
+                    .unwrap();
+                    eprintln!("{}/{}.phase1", temp.display(), test_name);
+                    let lowered = lower(&md, &options).unwrap();
+                    fs::write(
+                        temp.join(format!("{}.phase2", test_name)),
+                        format!("{lowered:#?}"),
+                    )
+                    .unwrap();
+                    eprintln!("{}/{}.phase2", temp.display(), test_name);
+                    let broken = &fix_line_breaks(lowered, &options);
+                    fs::write(
+                        temp.join(format!("{}.phase3", test_name)),
+                        format!("{broken:#?}"),
+                    )
+                    .unwrap();
+                    eprintln!("{}/{}.phase3", temp.display(), test_name);
+                    let actual_output = lowered_to_text(broken, &options).unwrap();
+                    fs::write(
+                        temp.join(format!("{}.actual.md", test_name)),
+                        &actual_output,
+                    )
+                    .unwrap();
+                    eprintln!("{}/{}.actual.md", temp.display(), test_name);
+                    fs::write(
+                        temp.join(format!("{}.actual.raw", test_name)),
+                        format!("{actual_output:#?}"),
+                    )
+                    .unwrap();
+                    eprintln!("{}/{}.actual.raw", temp.display(), test_name);
+                    fs::write(
+                        temp.join(format!("{}.out.raw", test_name)),
+                        format!("{expected_output:#?}"),
+                    )
+                    .unwrap();
+                    eprintln!("{}/{}.out.raw", temp.display(), test_name);
+
+                    assert_eq!(expected_output, actual_output);
+                    verify_equivalent(&input, &actual_output, &options).unwrap();
+                    assert_eq!(
+                        actual_output,
+                        format_str(&actual_output, &options).unwrap(),
+                        "formatting the output again changes it"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_formerly_unsupported() {
+        let input = "<div>\n  raw\n</div>\n\n- [x] done\n\n| a |\n|---|\n";
+        let output = format_str(input, &Options::default()).unwrap();
+        assert_eq!(
+            output,
+            "<div>\n  raw\n</div>\n\n*   [x] done\n\n| a   |\n| --- |\n"
+        );
+    }
+
+    #[test]
+    fn test_hr_width() {
+        let options = Options {
+            width: 40,
+            ..Options::default()
+        };
+        let text = lowered_to_text(&[Str("a"), Hr, Str("b")], &options).unwrap();
+        assert_eq!(text, format!("a\n\n{}\n\nb", "-".repeat(40)));
+    }
+
+    #[test]
+    fn test_unbalanced_directives() {
+        let input = "a\n\n<!-- markdown-format on -->\n\nb\n\n<!-- markdown-format off -->\n\nc\n";
+        let messages: Vec<_> = warnings(input).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "line 3: `<!-- markdown-format on -->` without `off` before it",
+                "line 7: `<!-- markdown-format off -->` is not turned on again, \
+                 the rest is not formatted",
+            ]
+        );
+        assert!(warnings("<!-- markdown-format off -->\n<!-- markdown-format on -->\n").is_empty());
+    }
+
+    // fn pass1(md: &str, expected: &[Lowered]) {
+    //     let input = md.replace("\n            ", "\n");
+    //     let md = markdown::tokenize(&input);
+    //     let mut buffer = LoweredBuffer::new();
+    //     lower_blocks(&mut buffer, &md);
+    //     if buffer.buffer != expected {
+    //         for (i, (actual, expected)) in buffer.buffer.iter().zip(expected).enumerate() {
+    //             if actual != expected {
+    //                 panic!("Formatted not as expected in line {i}\nexpected: {expected:?}\nactual:   {actual:?}\n");
+    //             }
+    //         }
+    //     }
+    // }
+
+    // macro_rules! mdtest {
+    //     ($input:literal, $output:literal) => {
+    //         let input = $input;
+    //         let output = $output;
+    //
+    //         let input: &str = &input;
+    //         let md = markdown::tokenize(&input);
+    //         eprintln!("@@@@@ Markdown\n{md:?}");
+    //         let lowered = lower(&md);
+    //         eprintln!("@@@@@ Lowered\n{lowered:?}");
+    //         let broken = &fix_line_breaks(lowered);
+    //         eprintln!("@@@@@ Broken\n{broken:?}");
+    //         let s = lowered_to_text(broken);
+    //         let formatted = s;
+    //
+    //         eprintln!("@@@@@ expected text\n{output:?}");
+    //         eprintln!("@@@@@ actual text\n{formatted:?}");
+    //
+    //         if formatted != output {
+    //             let mut formatted_lines = formatted.lines();
+    //             let mut output_lines = output.lines();
+    //
+    //             for i in 0.. {
+    //                 let expected = output_lines.next();
+    //                 let actual = formatted_lines.next();
+    //
+    //                 if expected.is_none() {
+    //                     if actual.is_none() {
+    //                         break; // lines are equal, do char comparison next
+    //                     }
+    //                 }
+    //                 if actual != expected {
+    //                     eprintln!(
+    //                         "Difference in line {i}:\nexpected: {expected:?}\nactual  : {actual:?}"
+    //                     );
+    //                     panic!("line {i} differs");
+    //                 }
+    //             }
+    //             let mut formatted_chars = formatted.chars();
+    //             let mut output_chars = output.chars();
+    //             for i in 0.. {
+    //                 let expected = output_chars.next();
+    //                 let actual = formatted_chars.next();
+    //                 if expected.is_none() {
+    //                     if actual.is_none() {
+    //                         unreachable!("strings are different but all chars are equal");
+    //                     }
+    //                 }
+    //                 if actual != expected {
+    //                     panic!("char {i} differs: {actual:?} != {expected:?}");
+    //                 }
+    //             }
+    //         }
+    //     };
+    // }
+}
@@ -0,0 +1,227 @@
+use crate::{ErrorKind, FormatError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// Name of the configuration file
+pub const CONFIG_FILE_NAME: &str = ".markdown-format.toml";
+
+/// Settings that influence the formatted output
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Options {
+    /// Wrap lines longer than this
+    pub width: usize,
+
+    /// Marker of unordered list items
+    pub list_marker: ListMarker,
+
+    /// Delimiter of emphasized text
+    pub emphasis: EmphasisStyle,
+
+    /// Delimiter of strong text
+    pub strong: StrongStyle,
+
+    /// How to write level 1 and 2 headings
+    pub heading: HeadingStyle,
+
+    /// Where footnote definitions go
+    pub footnotes: FootnotePlacement,
+
+    /// Where link reference definitions go
+    pub link_definitions: LinkDefinitions,
+
+    /// Turn inline links with long destinations into reference links
+    pub reference_links: bool,
+
+    /// How to write the keys of YAML or TOML front matter
+    pub front_matter: FrontMatter,
+
+    /// Read `$math$` and `$$math$$`
+    pub math: bool,
+
+    /// Where lines of text are broken
+    pub wrap: WrapStyle,
+
+    /// How lines that are too long are wrapped
+    pub line_breaks: LineBreaks,
+
+    /// Language of the text, to know where sentences end
+    pub language: String,
+
+    /// Abbreviations for each language in addition to the known ones
+    pub abbreviations: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            width: 80,
+            list_marker: ListMarker::Star,
+            emphasis: EmphasisStyle::Star,
+            strong: StrongStyle::Underscore,
+            heading: HeadingStyle::Setext,
+            footnotes: FootnotePlacement::Keep,
+            link_definitions: LinkDefinitions::Sort,
+            reference_links: false,
+            front_matter: FrontMatter::Keep,
+            math: false,
+            wrap: WrapStyle::Sentence,
+            line_breaks: LineBreaks::Greedy,
+            language: "en".to_owned(),
+            abbreviations: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum ListMarker {
+    #[serde(rename = "*")]
+    Star,
+    #[serde(rename = "-")]
+    Dash,
+    #[serde(rename = "+")]
+    Plus,
+}
+
+impl ListMarker {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            ListMarker::Star => "*",
+            ListMarker::Dash => "-",
+            ListMarker::Plus => "+",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum EmphasisStyle {
+    #[serde(rename = "*")]
+    Star,
+    #[serde(rename = "_")]
+    Underscore,
+}
+
+impl EmphasisStyle {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            EmphasisStyle::Star => "*",
+            EmphasisStyle::Underscore => "_",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum StrongStyle {
+    #[serde(rename = "**")]
+    Star,
+    #[serde(rename = "__")]
+    Underscore,
+}
+
+impl StrongStyle {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            StrongStyle::Star => "**",
+            StrongStyle::Underscore => "__",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadingStyle {
+    /// Underline level 1 and 2 headings with `=` and `-`
+    Setext,
+
+    /// Prefix all headings with `#`
+    Atx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FootnotePlacement {
+    /// Leave definitions where they are
+    Keep,
+
+    /// Move definitions to the end of the document, numbered in the order
+    /// of their first reference
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkDefinitions {
+    /// Leave definitions where they are
+    Keep,
+
+    /// Collect the definitions at the end of each section, sorted by label
+    Sort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrontMatter {
+    /// Copy the front matter as it is
+    Keep,
+
+    /// Sort the top level keys
+    Sort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WrapStyle {
+    /// Break after each sentence and clause, and wrap lines that are still
+    /// too long between inline elements
+    Sentence,
+
+    /// Put as many words on each line as fit
+    Fill,
+
+    /// Keep the line breaks of the input
+    Preserve,
+
+    /// Write each paragraph on a single line
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineBreaks {
+    /// Wrap where the next word would not fit on the line
+    Greedy,
+
+    /// Choose the breaks of a whole paragraph, so that its lines are about
+    /// equally long and the last line is not too short
+    Optimal,
+}
+
+impl Options {
+    /// Read options from the configuration file `path`
+    pub fn load(path: &Path) -> Result<Self, FormatError> {
+        let content = fs::read_to_string(path).map_err(|e| FormatError::from(e).with_path(path))?;
+        toml::from_str(&content)
+            .map_err(|e| FormatError::new(ErrorKind::Config(Box::new(e))).with_path(path))
+    }
+}
+
+/// Find the configuration file for `path`.
+///
+/// Search the directories above `path`, stopping at the root of the git
+/// repository.
+pub fn find_config(path: &Path) -> io::Result<Option<PathBuf>> {
+    let path = std::path::absolute(path)?;
+    for dir in path.ancestors().skip(1) {
+        let config = dir.join(CONFIG_FILE_NAME);
+        if config.is_file() {
+            return Ok(Some(config));
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,650 @@
+//! Parse markdown into the tree that is formatted.
+//!
+//! The events of pulldown-cmark are collected into blocks and spans, so the
+//! formatter and the equivalence check can work on a whole tree.
+
+use crate::front_matter;
+use crate::options::Options;
+use pulldown_cmark::{
+    Alignment, BlockQuoteKind, CodeBlockKind, Event, LinkType, Options as ParserOptions, Parser,
+    Tag,
+};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Block<'a> {
+    /// YAML or TOML at the start of the document, with its delimiters
+    FrontMatter(Cow<'a, str>),
+
+    /// Heading with level 1 to 6
+    Heading(usize, Vec<Span<'a>>),
+
+    Paragraph(Vec<Span<'a>>),
+
+    /// Text of a tight list item, which is not wrapped in a paragraph
+    Plain(Vec<Span<'a>>),
+
+    /// Quote, with the kind of a GitHub alert like `NOTE`
+    Blockquote(Option<&'static str>, Vec<Block<'a>>),
+
+    /// Fenced code with its info string, or indented code if there is none
+    Code(Option<Cow<'a, str>>, String),
+
+    /// Ordered list with its first number, or a bullet list
+    List(Option<u64>, Vec<Item<'a>>),
+
+    /// Raw HTML, with a newline after each line
+    Html(String),
+
+    /// Source from `<!-- markdown-format off -->` to `on`, which is copied as it is
+    Verbatim(&'a str),
+
+    FootnoteDefinition(Cow<'a, str>, Vec<Block<'a>>),
+
+    /// Link reference definition with its label, destination and title
+    Definition(Cow<'a, str>, Cow<'a, str>, Cow<'a, str>),
+
+    /// Alignment of each column, the header row and the other rows
+    Table(Vec<Alignment>, Vec<Cell<'a>>, Vec<Vec<Cell<'a>>>),
+
+    Hr,
+}
+
+pub(crate) type Cell<'a> = Vec<Span<'a>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Item<'a> {
+    /// Whether the checkbox of a task list item is checked
+    pub task: Option<bool>,
+
+    pub blocks: Vec<Block<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Span<'a> {
+    /// Text as written in the document, including escapes and entities
+    Text(Cow<'a, str>),
+
+    /// A line break in the document that is only whitespace
+    SoftBreak,
+
+    HardBreak,
+
+    Code(Cow<'a, str>),
+
+    Emphasis(Vec<Span<'a>>),
+
+    Strong(Vec<Span<'a>>),
+
+    Strikethrough(Vec<Span<'a>>),
+
+    /// Link text, destination, title and how the destination is given
+    Link(Vec<Span<'a>>, Cow<'a, str>, Cow<'a, str>, LinkKind<'a>),
+
+    /// Alt text, destination, title and how the destination is given
+    Image(Vec<Span<'a>>, Cow<'a, str>, Cow<'a, str>, LinkKind<'a>),
+
+    /// `<https://example.com>` or `<user@example.com>`
+    Autolink(Cow<'a, str>),
+
+    /// `$math$`
+    Math(Cow<'a, str>),
+
+    /// `$$math$$`, which may be on lines of its own
+    DisplayMath(Cow<'a, str>),
+
+    /// Inline HTML tag or comment, without the indentation and quote markers
+    /// of the lines after the first
+    Html(Cow<'a, str>),
+
+    FootnoteReference(Cow<'a, str>),
+}
+
+/// How a link or image gives its destination, with the label of references
+/// as written in the document
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum LinkKind<'a> {
+    /// `[text](url "title")`
+    Inline,
+
+    /// `[text][label]`
+    Full(Cow<'a, str>),
+
+    /// `[label][]`
+    Collapsed(Cow<'a, str>),
+
+    /// `[label]`
+    Shortcut(Cow<'a, str>),
+}
+
+/// Parse a CommonMark document with the GitHub extensions
+pub(crate) fn parse<'a>(source: &'a str, options: &Options) -> Vec<Block<'a>> {
+    let (front_matter, body) = front_matter::split(source);
+    let mut blocks: Vec<_> = front_matter
+        .map(|front_matter| Block::FrontMatter(Cow::Borrowed(front_matter)))
+        .into_iter()
+        .collect();
+    blocks.extend(reader(body, options.math).blocks());
+    blocks
+}
+
+/// Offsets of `markdown-format off` directives that are not turned on again
+/// and of `on` directives that do not follow an `off`, with the directive
+pub(crate) fn unbalanced_directives(source: &str) -> Vec<(usize, &'static str)> {
+    let (_, body) = front_matter::split(source);
+    let mut reader = reader(body, false);
+    reader.blocks();
+    let offset = source.len() - body.len();
+    reader
+        .unbalanced
+        .into_iter()
+        .map(|(start, directive)| (offset + start, directive))
+        .collect()
+}
+
+fn reader(source: &str, math: bool) -> Reader<'_> {
+    let mut options = ParserOptions::empty();
+    options.insert(ParserOptions::ENABLE_TABLES);
+    options.insert(ParserOptions::ENABLE_FOOTNOTES);
+    options.insert(ParserOptions::ENABLE_STRIKETHROUGH);
+    options.insert(ParserOptions::ENABLE_TASKLISTS);
+    options.insert(ParserOptions::ENABLE_GFM);
+    if math {
+        options.insert(ParserOptions::ENABLE_MATH);
+    }
+
+    // definitions are not events, they are put back where they were written
+    let definitions = definitions(source, options);
+    let parser = Parser::new_ext(source, options).into_offset_iter();
+
+    Reader {
+        events: parser.collect(),
+        position: 0,
+        source,
+        definitions: definitions.into(),
+        containers: 0,
+        unbalanced: Vec::new(),
+    }
+}
+
+/// The link reference definitions in `source` by position, including the ones
+/// whose label is already defined, which the parser does not report.
+fn definitions<'a>(source: &str, options: ParserOptions) -> Vec<(usize, Block<'a>)> {
+    let mut definitions = Vec::new();
+    let mut source = Cow::Borrowed(source);
+    loop {
+        let found: Vec<_> = Parser::new_ext(&source, options)
+            .reference_definitions()
+            .iter()
+            .map(|(label, definition)| {
+                let title = definition.title.as_deref().unwrap_or_default();
+                let block = Block::Definition(
+                    Cow::Owned(label.to_owned()),
+                    Cow::Owned(definition.dest.to_string()),
+                    Cow::Owned(title.to_owned()),
+                );
+                (definition.span.clone(), block)
+            })
+            .collect();
+        if found.is_empty() {
+            break;
+        }
+        // blank out the definitions that were found, so that later ones with
+        // the same label are found next, at the same offsets
+        let mut blanked = source.into_owned();
+        for (span, _) in &found {
+            let spaces: String = blanked[span.clone()]
+                .chars()
+                .map(|c| match c {
+                    '\n' => "\n".to_owned(),
+                    c => " ".repeat(c.len_utf8()),
+                })
+                .collect();
+            blanked.replace_range(span.clone(), &spaces);
+        }
+        source = Cow::Owned(blanked);
+        definitions.extend(found.into_iter().map(|(span, block)| (span.start, block)));
+    }
+    definitions.sort_by_key(|(start, _)| *start);
+    definitions
+}
+
+struct Reader<'a> {
+    events: Vec<(Event<'a>, Range<usize>)>,
+    position: usize,
+    source: &'a str,
+
+    /// Link reference definitions that are not yet in a block, by position
+    definitions: VecDeque<(usize, Block<'a>)>,
+
+    /// Number of quotes, list items and footnotes around the current block
+    containers: usize,
+
+    /// Offsets of directives without a partner
+    unbalanced: Vec<(usize, &'static str)>,
+}
+
+/// Ends a section that is copied as it is
+const ON_DIRECTIVE: &str = "<!-- markdown-format on -->";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Directive {
+    Off,
+    On,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<&Event<'a>> {
+        self.events.get(self.position).map(|(event, _)| event)
+    }
+
+    fn next(&mut self) -> Option<(Event<'a>, Range<usize>)> {
+        let event = self.events.get(self.position).cloned();
+        self.position += 1;
+        event
+    }
+
+    /// Blocks up to the end of the enclosing tag, which is consumed
+    fn blocks(&mut self) -> Vec<Block<'a>> {
+        let mut blocks = Vec::new();
+        loop {
+            let position = match self.events.get(self.position) {
+                None => usize::MAX,
+                Some((Event::End(_), range)) => range.end,
+                Some((_, range)) => range.start,
+            };
+            while let Some((start, _)) = self.definitions.front() {
+                if *start >= position {
+                    break;
+                }
+                if let Some((_, definition)) = self.definitions.pop_front() {
+                    blocks.push(definition);
+                }
+            }
+
+            match self.peek() {
+                None => break,
+                Some(Event::End(_)) => {
+                    self.next();
+                    break;
+                }
+                Some(event) if is_inline(event) => blocks.push(Block::Plain(self.inline())),
+                Some(Event::Start(Tag::HtmlBlock)) if self.containers == 0 => {
+                    match self.directive(self.position) {
+                        Some(Directive::Off) => blocks.push(self.verbatim()),
+                        Some(Directive::On) => {
+                            let start = self.events[self.position].1.start;
+                            self.unbalanced.push((start, "on"));
+                            blocks.extend(self.block());
+                        }
+                        None => blocks.extend(self.block()),
+                    }
+                }
+                Some(_) => {
+                    if let Some(block) = self.block() {
+                        blocks.push(block);
+                    }
+                }
+            }
+        }
+        blocks
+    }
+
+    fn block(&mut self) -> Option<Block<'a>> {
+        let (event, _) = self.next()?;
+        let block = match event {
+            Event::Start(Tag::Paragraph) => Block::Paragraph(self.spans()),
+            Event::Start(Tag::Heading { level, .. }) => {
+                Block::Heading(level as usize, self.spans())
+            }
+            Event::Start(Tag::BlockQuote(kind)) => {
+                let kind = kind.map(|kind| match kind {
+                    BlockQuoteKind::Note => "NOTE",
+                    BlockQuoteKind::Tip => "TIP",
+                    BlockQuoteKind::Important => "IMPORTANT",
+                    BlockQuoteKind::Warning => "WARNING",
+                    BlockQuoteKind::Caution => "CAUTION",
+                });
+                Block::Blockquote(kind, self.container())
+            }
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let info = match kind {
+                    CodeBlockKind::Fenced(info) => Some(info.into()),
+                    CodeBlockKind::Indented => None,
+                };
+                Block::Code(info, self.text())
+            }
+            Event::Start(Tag::HtmlBlock) => Block::Html(self.text()),
+            Event::Start(Tag::List(start)) => {
+                let mut items = Vec::new();
+                while let Some((event, _)) = self.next() {
+                    match event {
+                        Event::Start(Tag::Item) => {
+                            let task = self.task_list_marker();
+                            let blocks = self.container();
+                            items.push(Item { task, blocks });
+                        }
+                        _ => break,
+                    }
+                }
+                Block::List(start, items)
+            }
+            Event::Start(Tag::FootnoteDefinition(label)) => {
+                Block::FootnoteDefinition(label.into(), self.container())
+            }
+            Event::Start(Tag::Table(alignments)) => {
+                let mut header = Vec::new();
+                let mut rows = Vec::new();
+                while let Some((event, _)) = self.next() {
+                    match event {
+                        Event::Start(Tag::TableHead) => header = self.cells(),
+                        Event::Start(Tag::TableRow) => rows.push(self.cells()),
+                        _ => break,
+                    }
+                }
+                Block::Table(alignments, header, rows)
+            }
+            Event::Rule => Block::Hr,
+            Event::Start(_) => {
+                // not enabled in the parser
+                self.blocks();
+                return None;
+            }
+            _ => return None,
+        };
+        Some(block)
+    }
+
+    /// The directive in the HTML block that starts at event `index`
+    fn directive(&self, index: usize) -> Option<Directive> {
+        let (_, range) = self.events.get(index)?;
+        match self.source[range.clone()].trim() {
+            "<!-- markdown-format off -->" => Some(Directive::Off),
+            ON_DIRECTIVE => Some(Directive::On),
+            _ => None,
+        }
+    }
+
+    /// The source from an `off` directive up to the next `on` directive
+    /// in the same container, or the end of the document
+    fn verbatim(&mut self) -> Block<'a> {
+        let start = self.events[self.position].1.start;
+        let mut end = None;
+        let mut depth = 0;
+        let mut index = self.position;
+        while let Some((event, range)) = self.events.get(index) {
+            match event {
+                Event::Start(Tag::HtmlBlock)
+                    if depth == 0
+                        && index > self.position
+                        && self.directive(index) == Some(Directive::On) =>
+                {
+                    end = Some(range.end);
+                    depth += 1;
+                }
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                _ => {}
+            }
+            index += 1;
+            if depth == 0 && end.is_some() {
+                break;
+            }
+        }
+        let end = end.unwrap_or_else(|| {
+            self.unbalanced.push((start, "off"));
+            self.source.len()
+        });
+        self.position = index;
+        // definitions in there stay where they are
+        while self.definitions.front().is_some_and(|(d, _)| *d < end) {
+            self.definitions.pop_front();
+        }
+        Block::Verbatim(self.source[start..end].trim_end_matches('\n'))
+    }
+
+    /// Blocks of a quote, list item or footnote
+    fn container(&mut self) -> Vec<Block<'a>> {
+        self.containers += 1;
+        let blocks = self.blocks();
+        self.containers -= 1;
+        blocks
+    }
+
+    /// The checkbox at the start of a list item, which may be in a paragraph.
+    ///
+    /// The marker is left in the events and skipped with the text.
+    fn task_list_marker(&self) -> Option<bool> {
+        let offset = match self.peek() {
+            Some(Event::Start(Tag::Paragraph)) => 1,
+            _ => 0,
+        };
+        match self.events.get(self.position + offset) {
+            Some((Event::TaskListMarker(checked), _)) => Some(*checked),
+            _ => None,
+        }
+    }
+
+    /// Cells of a table row, up to the end of the row
+    fn cells(&mut self) -> Vec<Cell<'a>> {
+        let mut cells = Vec::new();
+        while let Some((event, _)) = self.next() {
+            match event {
+                Event::Start(Tag::TableCell) => cells.push(self.spans()),
+                _ => break,
+            }
+        }
+        cells
+    }
+
+    /// Literal text up to the end of the enclosing tag
+    fn text(&mut self) -> String {
+        let mut text = String::new();
+        while let Some((event, _)) = self.next() {
+            match event {
+                Event::Text(t) | Event::Html(t) => text.push_str(&t),
+                _ => break,
+            }
+        }
+        text
+    }
+
+    /// Spans up to the end of the enclosing tag, which is consumed
+    fn spans(&mut self) -> Vec<Span<'a>> {
+        let spans = self.inline();
+        self.next();
+        spans
+    }
+
+    /// Spans up to the next end tag or block
+    fn inline(&mut self) -> Vec<Span<'a>> {
+        let mut spans = Vec::new();
+        // source of the text that is collected into the last span
+        let mut text: Option<Range<usize>> = None;
+
+        while self.peek().is_some_and(is_inline) {
+            let Some((event, range)) = self.next() else {
+                break;
+            };
+            if let Event::Text(_) = event {
+                let gap = text
+                    .as_ref()
+                    .and_then(|t| self.source.get(t.end..range.start));
+                text = match text {
+                    // escaped characters start after their backslash
+                    Some(text) if matches!(gap, Some("" | "\\")) => {
+                        spans.pop();
+                        Some(text.start..range.end)
+                    }
+                    _ if self.source[..range.start].ends_with('\\') => {
+                        Some(range.start - 1..range.end)
+                    }
+                    _ => Some(range),
+                };
+                if let Some(text) = &text {
+                    spans.push(Span::Text(Cow::Borrowed(&self.source[text.clone()])));
+                }
+                continue;
+            }
+            text = None;
+
+            let span = match event {
+                Event::Code(code) => Span::Code(code.into()),
+                Event::InlineMath(math) => Span::Math(math.into()),
+                Event::DisplayMath(math) => Span::DisplayMath(math.into()),
+                Event::InlineHtml(html) if self.containers > 0 && html.contains('\n') => {
+                    // the source of the html includes the prefixes of the containers
+                    let mut lines = html.split('\n');
+                    let mut stripped = lines.next().unwrap_or_default().to_owned();
+                    for line in lines {
+                        stripped.push('\n');
+                        stripped.push_str(line.trim_start_matches([' ', '\t', '>']));
+                    }
+                    Span::Html(Cow::Owned(stripped))
+                }
+                Event::InlineHtml(html) => Span::Html(html.into()),
+                Event::SoftBreak => Span::SoftBreak,
+                Event::HardBreak => Span::HardBreak,
+                Event::FootnoteReference(label) => Span::FootnoteReference(label.into()),
+                Event::Start(Tag::Emphasis) => Span::Emphasis(self.spans()),
+                Event::Start(Tag::Strong) => Span::Strong(self.spans()),
+                Event::Start(Tag::Strikethrough) => Span::Strikethrough(self.spans()),
+                Event::Start(Tag::Link {
+                    link_type: LinkType::Autolink | LinkType::Email,
+                    dest_url,
+                    ..
+                }) => {
+                    self.spans();
+                    Span::Autolink(dest_url.into())
+                }
+                Event::Start(Tag::Link {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }) => Span::Link(
+                    self.spans(),
+                    dest_url.into(),
+                    title.into(),
+                    link_kind(link_type, id.into()),
+                ),
+                Event::Start(Tag::Image {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }) => Span::Image(
+                    self.spans(),
+                    dest_url.into(),
+                    title.into(),
+                    link_kind(link_type, id.into()),
+                ),
+                Event::Start(_) => {
+                    // not enabled in the parser
+                    spans.extend(self.spans());
+                    continue;
+                }
+                _ => continue,
+            };
+            spans.push(span);
+        }
+        spans
+    }
+}
+
+fn link_kind(link_type: LinkType, label: Cow<str>) -> LinkKind {
+    match link_type {
+        LinkType::Reference | LinkType::ReferenceUnknown => LinkKind::Full(label),
+        LinkType::Collapsed | LinkType::CollapsedUnknown => LinkKind::Collapsed(label),
+        LinkType::Shortcut | LinkType::ShortcutUnknown => LinkKind::Shortcut(label),
+        _ => LinkKind::Inline,
+    }
+}
+
+/// Append `new` to `blocks`, but in front of an `off` section that is never
+/// turned on again, which would copy them as part of itself
+pub(crate) fn append_before_verbatim<'a>(
+    blocks: &mut Vec<Block<'a>>,
+    new: impl IntoIterator<Item = Block<'a>>,
+) {
+    let at = match blocks.last() {
+        Some(Block::Verbatim(text))
+            if text.lines().next_back().map(str::trim) != Some(ON_DIRECTIVE) =>
+        {
+            blocks.len() - 1
+        }
+        _ => blocks.len(),
+    };
+    blocks.splice(at..at, new);
+}
+
+/// Call `f` with every span in `blocks`, in document order
+pub(crate) fn visit_spans<'a>(blocks: &mut [Block<'a>], f: &mut dyn FnMut(&mut Span<'a>)) {
+    for block in blocks {
+        match block {
+            Block::Heading(_, spans) | Block::Paragraph(spans) | Block::Plain(spans) => {
+                visit_inline(spans, f)
+            }
+            Block::Blockquote(_, blocks) | Block::FootnoteDefinition(_, blocks) => {
+                visit_spans(blocks, f)
+            }
+            Block::List(_, items) => {
+                for item in items {
+                    visit_spans(&mut item.blocks, f);
+                }
+            }
+            Block::Table(_, header, rows) => {
+                for cell in header.iter_mut().chain(rows.iter_mut().flatten()) {
+                    visit_inline(cell, f);
+                }
+            }
+            Block::FrontMatter(_)
+            | Block::Code(..)
+            | Block::Html(_)
+            | Block::Verbatim(_)
+            | Block::Definition(..)
+            | Block::Hr => {}
+        }
+    }
+}
+
+fn visit_inline<'a>(spans: &mut [Span<'a>], f: &mut dyn FnMut(&mut Span<'a>)) {
+    for span in spans {
+        f(span);
+        match span {
+            Span::Emphasis(spans)
+            | Span::Strong(spans)
+            | Span::Strikethrough(spans)
+            | Span::Link(spans, ..)
+            | Span::Image(spans, ..) => visit_inline(spans, f),
+            _ => {}
+        }
+    }
+}
+
+fn is_inline(event: &Event) -> bool {
+    match event {
+        Event::Start(tag) => matches!(
+            tag,
+            Tag::Emphasis
+                | Tag::Strong
+                | Tag::Strikethrough
+                | Tag::Superscript
+                | Tag::Subscript
+                | Tag::Link { .. }
+                | Tag::Image { .. }
+        ),
+        Event::Text(_)
+        | Event::Code(_)
+        | Event::InlineMath(_)
+        | Event::DisplayMath(_)
+        | Event::InlineHtml(_)
+        | Event::FootnoteReference(_)
+        | Event::SoftBreak
+        | Event::HardBreak
+        | Event::TaskListMarker(_) => true,
+        Event::End(_) | Event::Html(_) | Event::Rule => false,
+    }
+}
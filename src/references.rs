@@ -0,0 +1,194 @@
+//! Link reference definitions: sorted at the end of each section, and long
+//! inline links turned into references.
+//!
+//! A section is everything from one heading up to the next one.
+
+use crate::options::{LinkDefinitions, Options};
+use crate::parse::{append_before_verbatim, visit_spans, Block, LinkKind, Span};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+lazy_static! {
+    /// Text in brackets that would become a link to a new definition
+    static ref RE_BRACKETED: Regex = Regex::new(r"\[\s*([^\[\]\s]+)\s*\]").unwrap();
+}
+
+/// Inline links with a longer destination become references
+const LONG_URL_LENGTH: usize = 40;
+
+/// Arrange the link reference definitions in `blocks` as configured
+pub(crate) fn arrange_definitions<'a>(
+    blocks: Vec<Block<'a>>,
+    source: &str,
+    options: &Options,
+) -> Vec<Block<'a>> {
+    if options.link_definitions == LinkDefinitions::Keep && !options.reference_links {
+        return blocks;
+    }
+    let mut labels = Labels::collect(&blocks, source);
+
+    let mut result = Vec::with_capacity(blocks.len());
+    let mut section = Vec::new();
+    for block in blocks {
+        if matches!(block, Block::Heading(..)) {
+            finish_section(
+                &mut result,
+                std::mem::take(&mut section),
+                &mut labels,
+                options,
+            );
+        }
+        section.push(block);
+    }
+    finish_section(&mut result, section, &mut labels, options);
+    result
+}
+
+/// The label in lowercase and with single spaces.
+///
+/// Only ASCII letters are lowercased, other letters could fold differently
+/// when labels are matched.
+pub(crate) fn normalize_label(label: &str) -> String {
+    label
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_ascii_lowercase()
+}
+
+fn finish_section<'a>(
+    result: &mut Vec<Block<'a>>,
+    mut section: Vec<Block<'a>>,
+    labels: &mut Labels,
+    options: &Options,
+) {
+    let mut definitions = Vec::new();
+    if options.reference_links {
+        visit_spans(&mut section, &mut |span| {
+            if let Span::Link(_, url, title, kind @ LinkKind::Inline) = span {
+                if url.len() > LONG_URL_LENGTH {
+                    let label = labels.reference_to(url, title, &mut definitions);
+                    *kind = LinkKind::Full(Cow::Owned(label));
+                }
+            }
+        });
+    }
+    if options.link_definitions == LinkDefinitions::Sort {
+        section = take_definitions(section, &mut definitions);
+        definitions.sort_by_cached_key(|definition| match definition {
+            Block::Definition(label, ..) => normalize_label(label),
+            _ => String::new(),
+        });
+    }
+    result.extend(section);
+    append_before_verbatim(result, definitions);
+}
+
+/// Labels of the definitions in the document
+#[derive(Default)]
+struct Labels {
+    /// Labels that are defined or written in brackets
+    used: HashSet<String>,
+
+    /// Label of each destination and title
+    targets: HashMap<(String, String), String>,
+
+    /// Number tried first for a new label
+    next: usize,
+}
+
+impl Labels {
+    fn collect(blocks: &[Block], source: &str) -> Self {
+        let mut labels = Labels::default();
+        labels.add(blocks);
+        for captures in RE_BRACKETED.captures_iter(source) {
+            labels.used.insert(normalize_label(&captures[1]));
+        }
+        labels
+    }
+
+    fn add(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            match block {
+                Block::Definition(label, url, title) => {
+                    let label = normalize_label(label);
+                    self.targets
+                        .entry((url.to_string(), title.to_string()))
+                        .or_insert_with(|| label.clone());
+                    self.used.insert(label);
+                }
+                Block::Blockquote(_, blocks) | Block::FootnoteDefinition(_, blocks) => {
+                    self.add(blocks)
+                }
+                Block::List(_, items) => {
+                    for item in items {
+                        self.add(&item.blocks);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The label of a definition of `url` and `title`, which is added to
+    /// `definitions` if there is none yet
+    fn reference_to<'a>(
+        &mut self,
+        url: &str,
+        title: &str,
+        definitions: &mut Vec<Block<'a>>,
+    ) -> String {
+        let target = (url.to_owned(), title.to_owned());
+        if let Some(label) = self.targets.get(&target) {
+            return label.clone();
+        }
+        let label = loop {
+            self.next += 1;
+            let label = self.next.to_string();
+            if !self.used.contains(&label) {
+                break label;
+            }
+        };
+        self.used.insert(label.clone());
+        self.targets.insert(target, label.clone());
+        definitions.push(Block::Definition(
+            Cow::Owned(label.clone()),
+            Cow::Owned(url.to_owned()),
+            Cow::Owned(title.to_owned()),
+        ));
+        label
+    }
+}
+
+/// Remove the definitions from `blocks` and everything in them
+fn take_definitions<'a>(
+    blocks: Vec<Block<'a>>,
+    definitions: &mut Vec<Block<'a>>,
+) -> Vec<Block<'a>> {
+    let mut result = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        match block {
+            Block::Definition(..) => definitions.push(block),
+            Block::Blockquote(kind, content) => {
+                result.push(Block::Blockquote(
+                    kind,
+                    take_definitions(content, definitions),
+                ));
+            }
+            Block::FootnoteDefinition(label, content) => {
+                let content = take_definitions(content, definitions);
+                result.push(Block::FootnoteDefinition(label, content));
+            }
+            Block::List(start, mut items) => {
+                for item in &mut items {
+                    item.blocks = take_definitions(std::mem::take(&mut item.blocks), definitions);
+                }
+                result.push(Block::List(start, items));
+            }
+            block => result.push(block),
+        }
+    }
+    result
+}
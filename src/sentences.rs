@@ -0,0 +1,111 @@
+//! Where sentences and clauses end, so that each can start a new line.
+//!
+//! A period after an abbreviation, an initial or an ordinal number does not
+//! end a sentence, and neither does a comma in a list of numbers. Numbers,
+//! versions and domains like `3.14`, `v1.2.3` and `example.com` have no
+//! whitespace after their periods, so they are never broken.
+
+use crate::options::Options;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashSet;
+
+lazy_static! {
+    /// Initials and abbreviations made of them, like `J.`, `U.S.` or `z.B.`
+    static ref RE_INITIALS: Regex = Regex::new(r"^(\p{L}\.)+$").unwrap();
+
+    /// A number like `3`, `1,000` or `2.5`
+    static ref RE_NUMBER: Regex = Regex::new(r"^[-+]?\d+([.,]\d+)*$").unwrap();
+}
+
+/// Abbreviations of each language that are known without configuration
+const ABBREVIATIONS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "al.", "approx.", "Apr.", "Aug.", "ca.", "cf.", "Ch.", "Co.", "Corp.", "Dec.", "Dr.",
+            "e.g.", "Eq.", "etc.", "Feb.", "Fig.", "Figs.", "i.e.", "Inc.", "Jan.", "Jr.", "Jul.",
+            "Jun.", "Ltd.", "Mar.", "Mr.", "Mrs.", "Ms.", "No.", "Nos.", "Nov.", "Oct.", "p.",
+            "pp.", "Prof.", "Sec.", "Sep.", "Sept.", "Sr.", "St.", "vs.", "Vol.",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "Abb.", "Abs.", "bspw.", "bzgl.", "bzw.", "ca.", "d.h.", "Dr.", "etc.", "evtl.",
+            "exkl.", "Fr.", "ggf.", "Hr.", "inkl.", "Jh.", "Kap.", "Mio.", "Mrd.", "Nr.", "o.ä.",
+            "Prof.", "S.", "sog.", "Str.", "Tab.", "u.a.", "u.ä.", "u.U.", "usw.", "vgl.", "z.B.",
+            "z.T.",
+        ],
+    ),
+];
+
+/// Languages that write ordinal numbers with a period, like `3. Oktober`
+const ORDINALS_WITH_PERIOD: &[&str] = &["de"];
+
+/// Quotes, brackets and emphasis after the punctuation that ends a sentence
+const CLOSING: &[char] = &['"', '\'', ')', ']', '*', '_', '’', '”', '»'];
+
+/// Quotes, brackets and emphasis before the first word of a sentence
+const OPENING: &[char] = &['"', '\'', '(', '[', '*', '_', '‘', '“', '«'];
+
+/// Finds the ends of sentences and clauses in the configured language
+pub(crate) struct Sentences {
+    abbreviations: HashSet<String>,
+
+    ordinals: bool,
+}
+
+impl Sentences {
+    pub fn new(options: &Options) -> Self {
+        let language = options.language.as_str();
+        let builtin = ABBREVIATIONS
+            .iter()
+            .filter(|(l, _)| *l == language)
+            .flat_map(|(_, abbreviations)| abbreviations.iter().copied());
+        let configured = options
+            .abbreviations
+            .get(language)
+            .into_iter()
+            .flatten()
+            .map(String::as_str);
+        let mut abbreviations = HashSet::new();
+        for abbreviation in builtin.chain(configured) {
+            // abbreviations can start a sentence too
+            let mut chars = abbreviation.chars();
+            if let Some(first) = chars.next() {
+                abbreviations.insert(first.to_uppercase().chain(chars).collect());
+            }
+            abbreviations.insert(abbreviation.to_owned());
+        }
+        Sentences {
+            abbreviations,
+            ordinals: ORDINALS_WITH_PERIOD.contains(&language),
+        }
+    }
+
+    /// Whether a sentence or clause ends after `word`, if `next` follows it
+    pub fn ends_after(&self, word: &str, next: &str) -> bool {
+        // words can be joined by spaces that are never broken
+        let word = word.rsplit(' ').next().unwrap_or(word);
+        let next = next.split(' ').next().unwrap_or(next);
+        let word = word.trim_end_matches(CLOSING);
+        let next = next.trim_start_matches(OPENING);
+        match word.chars().next_back() {
+            // lists of numbers like `1, 2, 3`
+            Some(',') => !(is_number(&word[..word.len() - 1]) && is_number(next)),
+            Some(';' | ':' | '!' | '?') => true,
+            Some('.') => {
+                let word = word.trim_start_matches(OPENING);
+                !(self.abbreviations.contains(word)
+                    || RE_INITIALS.is_match(word)
+                    || (self.ordinals && is_number(&word[..word.len() - 1])))
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_number(word: &str) -> bool {
+    RE_NUMBER.is_match(word.trim_end_matches([',', ';', ':', '.']))
+}
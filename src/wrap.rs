@@ -0,0 +1,329 @@
+//! Strategies that decide where the lines of a paragraph are broken.
+//!
+//! `fix_line_breaks` cuts the text of each paragraph into words at the
+//! whitespace between them, and the strategy chooses which of the
+//! whitespace becomes a line break.
+
+use crate::options::{LineBreaks, Options, WrapStyle};
+use crate::sentences::Sentences;
+
+/// Text that is never broken into lines
+#[derive(Debug)]
+pub(crate) struct Word {
+    pub text: String,
+
+    /// Display width of the text
+    pub width: usize,
+}
+
+/// Whitespace between two words
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Space {
+    /// Inside of text, not between inline elements like text and emphasis
+    pub in_text: bool,
+
+    /// Next to a link or something else that goes on a line of its own
+    pub apart: bool,
+
+    /// The input had a line break here
+    pub newline: bool,
+}
+
+impl Space {
+    /// Whitespace that is next to other whitespace
+    pub fn join(self, other: Space) -> Space {
+        Space {
+            in_text: self.in_text && other.in_text,
+            apart: self.apart || other.apart,
+            newline: self.newline || other.newline,
+        }
+    }
+}
+
+/// Where the lines of a paragraph go
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Layout {
+    /// Column where the first word starts
+    pub start: usize,
+
+    /// Column where the following lines start, after quote markers and
+    /// indentation
+    pub indent: usize,
+
+    /// Lines should not be wider than this
+    pub width: usize,
+}
+
+pub(crate) trait Strategy {
+    /// For each space between `words`, whether it becomes a line break
+    fn breaks(&self, words: &[Word], spaces: &[Space], layout: Layout) -> Vec<bool>;
+}
+
+/// The strategy configured in `options`
+pub(crate) fn strategy(options: &Options) -> Box<dyn Strategy> {
+    let breaker = options.line_breaks;
+    match options.wrap {
+        WrapStyle::Sentence => Box::new(Sentence(Sentences::new(options), breaker)),
+        WrapStyle::Fill => Box::new(Fill(breaker)),
+        WrapStyle::Preserve => Box::new(Preserve),
+        WrapStyle::Never => Box::new(Never),
+    }
+}
+
+/// Whether whitespace can become a line break
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Candidate {
+    Never,
+    May,
+    Must,
+}
+
+/// A line for each sentence and clause, and for each link. Lines that are
+/// still too long are broken between inline elements, or inside of text
+/// that does not fit on a line of its own.
+struct Sentence(Sentences, LineBreaks);
+
+impl Strategy for Sentence {
+    fn breaks(&self, words: &[Word], spaces: &[Space], layout: Layout) -> Vec<bool> {
+        let mut candidates: Vec<Candidate> = spaces
+            .iter()
+            .enumerate()
+            .map(|(i, space)| {
+                if space.apart || self.0.ends_after(&words[i].text, &words[i + 1].text) {
+                    Candidate::Must
+                } else if space.in_text {
+                    Candidate::Never
+                } else {
+                    Candidate::May
+                }
+            })
+            .collect();
+        // runs of words that are never broken, from `begin` to `end`
+        let mut begin = 0;
+        for end in 0..words.len() {
+            if candidates.get(end) == Some(&Candidate::Never) {
+                continue;
+            }
+            let start = if begin == 0 {
+                layout.start
+            } else {
+                layout.indent
+            };
+            if start + run_width(&words[begin..=end]) > layout.width {
+                candidates[begin..end].fill(Candidate::May);
+            }
+            begin = end + 1;
+        }
+        choose(self.1, words, &candidates, layout)
+    }
+}
+
+/// As many words on each line as fit
+struct Fill(LineBreaks);
+
+impl Strategy for Fill {
+    fn breaks(&self, words: &[Word], spaces: &[Space], layout: Layout) -> Vec<bool> {
+        choose(self.0, words, &vec![Candidate::May; spaces.len()], layout)
+    }
+}
+
+/// Choose line breaks among the `candidates` between `words`
+fn choose(
+    breaker: LineBreaks,
+    words: &[Word],
+    candidates: &[Candidate],
+    layout: Layout,
+) -> Vec<bool> {
+    match breaker {
+        LineBreaks::Greedy => greedy(words, candidates, layout),
+        LineBreaks::Optimal => {
+            // lines between breaks that must be made are chosen on their own
+            let mut breaks = Vec::with_capacity(candidates.len());
+            let mut start = 0;
+            for (i, &candidate) in candidates.iter().enumerate() {
+                if candidate == Candidate::Must {
+                    breaks.extend(optimal(
+                        &words[start..=i],
+                        &candidates[start..i],
+                        layout,
+                        start == 0,
+                    ));
+                    breaks.push(true);
+                    start = i + 1;
+                }
+            }
+            breaks.extend(optimal(
+                &words[start..],
+                &candidates[start..],
+                layout,
+                start == 0,
+            ));
+            breaks
+        }
+    }
+}
+
+/// Break where the text up to the next candidate would not fit on the line
+fn greedy(words: &[Word], candidates: &[Candidate], layout: Layout) -> Vec<bool> {
+    let mut breaks = Vec::with_capacity(candidates.len());
+    let mut line = layout.start;
+    for (i, &candidate) in candidates.iter().enumerate() {
+        line += words[i].width;
+        let line_break = match candidate {
+            Candidate::Must => true,
+            Candidate::Never => false,
+            Candidate::May if line > layout.width => true,
+            Candidate::May => {
+                // the words up to the next place where the line can be broken
+                let run = candidates[i + 1..]
+                    .iter()
+                    .take_while(|&&candidate| candidate == Candidate::Never)
+                    .count();
+                line + 1 + run_width(&words[i + 1..=i + 1 + run]) > layout.width
+            }
+        };
+        line = if line_break { layout.indent } else { line + 1 };
+        breaks.push(line_break);
+    }
+    breaks
+}
+
+/// Cost of each column that a line is too long
+const OVERFLOW: u64 = 100_000;
+
+/// Cost of a last line with a single word
+const WIDOW: u64 = 1_000;
+
+/// Cost of a first line with a single word, when more lines follow
+const ORPHAN: u64 = 1_000;
+
+/// Last lines that are narrower than this part of the width cost extra
+const SHORT_LAST_LINE: usize = 4;
+
+/// Break so that the lines are about equally long, by minimizing the sum of
+/// the squared space left at the end of each line, like Knuth and Plass.
+///
+/// `words` can be broken at the `May` candidates between them, `first` tells
+/// if they start at `layout.start` instead of the indentation.
+fn optimal(words: &[Word], candidates: &[Candidate], layout: Layout, first: bool) -> Vec<bool> {
+    // runs of words that are never broken, as (width, number of words)
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    // the candidate after each run
+    let mut ends = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        let joined = i > 0 && candidates[i - 1] == Candidate::Never;
+        match runs.last_mut() {
+            Some(run) if joined => {
+                run.0 += 1 + word.width;
+                run.1 += 1;
+            }
+            _ => {
+                if i > 0 {
+                    ends.push(i - 1);
+                }
+                runs.push((word.width, 1));
+            }
+        }
+    }
+
+    let n = runs.len();
+    // the cheapest cost of the lines up to each run, and where its line starts
+    let mut best: Vec<(u64, usize)> = vec![(0, 0); n + 1];
+    for end in 1..=n {
+        best[end] = (u64::MAX, 0);
+        // the width and number of words of the line from `begin` to `end`
+        let mut text = 0;
+        let mut words = 0;
+        for begin in (0..end).rev() {
+            text += runs[begin].0 + usize::from(begin + 1 < end);
+            words += runs[begin].1;
+            let start = if first && begin == 0 {
+                layout.start
+            } else {
+                layout.indent
+            };
+            let cost = line_cost(
+                start + text,
+                text,
+                words,
+                begin == 0,
+                end == n,
+                layout.width,
+            );
+            let total = best[begin].0.saturating_add(cost);
+            if total < best[end].0 {
+                best[end] = (total, begin);
+            }
+            // longer lines only get worse
+            if start + text > layout.width && end - begin > 1 {
+                break;
+            }
+        }
+    }
+
+    let mut breaks = vec![false; candidates.len()];
+    let mut end = n;
+    while end > 0 {
+        let begin = best[end].1;
+        if begin > 0 {
+            breaks[ends[begin - 1]] = true;
+        }
+        end = begin;
+    }
+    breaks
+}
+
+/// Cost of a line that ends in column `length`, with `words` in `text`
+/// columns of text
+fn line_cost(
+    length: usize,
+    text: usize,
+    words: usize,
+    first: bool,
+    last: bool,
+    width: usize,
+) -> u64 {
+    if length > width {
+        return OVERFLOW * (length - width) as u64;
+    }
+    let mut cost = 0;
+    if !last {
+        cost += ((width - length) as u64).pow(2);
+    }
+    if last && !first {
+        if words == 1 {
+            cost += WIDOW;
+        }
+        let short = width / SHORT_LAST_LINE;
+        if text < short {
+            cost += ((short - text) as u64).pow(2);
+        }
+    }
+    if first && !last && words == 1 {
+        cost += ORPHAN;
+    }
+    cost
+}
+
+/// The lines of the input
+struct Preserve;
+
+impl Strategy for Preserve {
+    fn breaks(&self, _words: &[Word], spaces: &[Space], _layout: Layout) -> Vec<bool> {
+        spaces.iter().map(|space| space.newline).collect()
+    }
+}
+
+/// Each paragraph on a single line
+struct Never;
+
+impl Strategy for Never {
+    fn breaks(&self, _words: &[Word], spaces: &[Space], _layout: Layout) -> Vec<bool> {
+        vec![false; spaces.len()]
+    }
+}
+
+/// Width of `words` on one line, with a space between each
+fn run_width(words: &[Word]) -> usize {
+    words.iter().map(|word| word.width).sum::<usize>() + words.len().saturating_sub(1)
+}